@@ -0,0 +1,898 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+const CPU_CLOCK: u32 = 4194304;
+
+pub const WAVE_RAM_SIZE: usize = 16;
+const REGISTER_COUNT: usize = 0x17;
+
+const SQUARE_LENGTH: u16 = 64;
+const WAVE_LENGTH: u16 = 256;
+const NOISE_LENGTH: u16 = 64;
+
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], //12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], //25%
+    [1, 0, 0, 0, 0, 1, 1, 1], //50%
+    [0, 1, 1, 1, 1, 1, 1, 0], //75%
+];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/* Bits that are write only or unused read back as 1, indexed from 0xFF10 */
+const READ_MASKS: [u8; REGISTER_COUNT] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, //NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, //unused, NR21-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, //NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, //unused, NR41-NR44
+    0x00, 0x00, 0x70,             //NR50-NR52
+];
+
+#[derive(Default)]
+struct LengthCounter {
+    enabled: bool,
+    counter: u16,
+}
+
+impl LengthCounter {
+    fn load(&mut self, max: u16, length: u16) {
+        self.counter = max - length;
+    }
+
+    /* Returns true when the counter ran out and the channel should stop */
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    /* NRx4 write. Enabling the counter while the frame sequencer is in the half
+     * that does not clock lengths gives it an extra clock, returns false if that
+     * clock ran the counter out */
+    fn write_control(&mut self, enable: bool, trigger: bool, max: u16, odd_step: bool) -> bool {
+        let mut keep_enabled = true;
+        if !self.enabled && enable && odd_step && self.counter > 0 {
+            self.counter -= 1;
+            keep_enabled = self.counter != 0 || trigger;
+        }
+        self.enabled = enable;
+        if trigger && self.counter == 0 {
+            self.counter = if enable && odd_step { max - 1 } else { max };
+        }
+        keep_enabled
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_u16(self.counter);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.counter = state.read_u16()?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Envelope {
+    initial_volume: u8,
+    increasing: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.increasing = (value & 0x08) != 0;
+        self.period = value & 0x07;
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer -= 1;
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increasing && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increasing && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.volume);
+        state.write_u8(self.timer);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.volume = state.read_u8()?;
+        self.timer = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow_frequency: u16,
+    //Clearing negate after a negated calculation disables the channel
+    negate_used: bool,
+}
+
+impl Sweep {
+    fn write(&mut self, value: u8) -> bool {
+        self.period = (value >> 4) & 0x07;
+        self.negate = (value & 0x08) != 0;
+        self.shift = value & 0x07;
+        !self.negate_used || self.negate
+    }
+
+    fn next_frequency(&mut self) -> u16 {
+        let delta = self.shadow_frequency >> self.shift;
+        if self.negate {
+            self.negate_used = true;
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.timer);
+        state.write_bool(self.enabled);
+        state.write_u16(self.shadow_frequency);
+        state.write_bool(self.negate_used);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.timer = state.read_u8()?;
+        self.enabled = state.read_bool()?;
+        self.shadow_frequency = state.read_u16()?;
+        self.negate_used = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct SquareChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    duty: u8,
+    duty_position: u8,
+    frequency: u16,
+    timer: u32,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl SquareChannel {
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    fn step(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.duty_position = (self.duty_position + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.timer = self.period();
+        self.envelope.trigger();
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        DUTY_PATTERNS[self.duty as usize][self.duty_position as usize] * self.envelope.volume
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_u8(self.duty_position);
+        state.write_u16(self.frequency);
+        state.write_u32(self.timer);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.duty_position = state.read_u8()? % 8;
+        self.frequency = state.read_u16()? & 0x7FF;
+        self.timer = state.read_u32()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)
+    }
+}
+
+#[derive(Default)]
+struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    output_level: u8,
+    frequency: u16,
+    timer: u32,
+    position: u8,
+    length: LengthCounter,
+    wave_ram: [u8; WAVE_RAM_SIZE],
+}
+
+impl WaveChannel {
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    fn step(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+        }
+        self.timer -= cycles;
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        //Two samples per byte, upper nibble first
+        let byte = self.wave_ram[self.position as usize / 2];
+        let sample = if self.position & 1 == 0 { byte >> 4 } else { byte & 0x0F };
+        //Level 0 mutes, 1-3 shift the sample right by 0-2
+        match self.output_level {
+            0 => 0,
+            level => sample >> (level - 1),
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_u16(self.frequency);
+        state.write_u32(self.timer);
+        state.write_u8(self.position);
+        self.length.save_state(state);
+        state.write_bytes(&self.wave_ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.frequency = state.read_u16()? & 0x7FF;
+        self.timer = state.read_u32()?;
+        self.position = state.read_u8()? % 32;
+        self.length.load_state(state)?;
+        state.read_bytes(&mut self.wave_ram)
+    }
+}
+
+#[derive(Default)]
+struct NoiseChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    clock_shift: u8,
+    width_mode: bool,
+    divisor: u8,
+    timer: u32,
+    lfsr: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl NoiseChannel {
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor as usize] << self.clock_shift
+    }
+
+    fn step(&mut self, mut cycles: u32) {
+        //Shifts of 14 and 15 stop the LFSR from being clocked
+        if self.clock_shift >= 14 {
+            return;
+        }
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+
+            let bit = (self.lfsr & 0b1) ^ ((self.lfsr >> 1) & 0b1);
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            if self.width_mode {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (bit << 6);
+            }
+        }
+        self.timer -= cycles;
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.timer = self.period();
+        self.lfsr = 0x7FFF;
+        self.envelope.trigger();
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled || (self.lfsr & 0b1) != 0 {
+            return 0;
+        }
+        self.envelope.volume
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enabled);
+        state.write_u32(self.timer);
+        state.write_u16(self.lfsr);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.read_bool()?;
+        self.timer = state.read_u32()?;
+        self.lfsr = state.read_u16()? & 0x7FFF;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)
+    }
+}
+
+pub struct APU {
+    /* Sound registers 0xFF10-0xFF26, wave RAM 0xFF30-0xFF3F
+     * Channel 1 square wave with frequency sweep
+     * Channel 2 square wave
+     * Channel 3 4 bit samples from wave RAM
+     * Channel 4 noise from a linear feedback shift register
+     *
+     * Frame sequencer, clocked at 512Hz by bit 4 of DIV:
+     * step 0 2 4 6 length counters
+     * step 2 6     sweep
+     * step 7       volume envelopes
+     */
+    registers: [u8; REGISTER_COUNT],
+    power: bool,
+    frame_sequencer: u8,
+
+    square_1: SquareChannel,
+    sweep: Sweep,
+    square_2: SquareChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+
+    sample_rate: u32,
+    sample_cycles: u32,
+    //Capacitor charge of the high pass filter on each output
+    capacitor: (f32, f32),
+    //Interleaved left and right samples in the range -1.0 to 1.0
+    samples: Vec<f32>,
+}
+
+impl APU {
+    pub fn new(sample_rate: u32) -> APU {
+        APU {
+            registers: [0; REGISTER_COUNT],
+            power: false,
+            frame_sequencer: 0,
+
+            square_1: Default::default(),
+            sweep: Default::default(),
+            square_2: Default::default(),
+            wave: Default::default(),
+            noise: Default::default(),
+
+            sample_rate,
+            sample_cycles: 0,
+            capacitor: (0.0, 0.0),
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.sample_cycles = 0;
+        self.samples.clear();
+    }
+
+    /* Hands out the samples generated since the last call, left and right interleaved */
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn step(&mut self, cycles: u16) {
+        let cycles = cycles as u32;
+        if self.power {
+            self.square_1.step(cycles);
+            self.square_2.step(cycles);
+            self.wave.step(cycles);
+            self.noise.step(cycles);
+        }
+
+        self.sample_cycles += cycles * self.sample_rate;
+        while self.sample_cycles >= CPU_CLOCK {
+            self.sample_cycles -= CPU_CLOCK;
+            self.output_sample();
+        }
+    }
+
+    /* Called on the falling edge of DIV bit 4 */
+    pub fn clock_frame_sequencer(&mut self) {
+        if !self.power {
+            return;
+        }
+
+        match self.frame_sequencer {
+            0 | 4 => self.clock_lengths(),
+            2 | 6 => {
+                self.clock_lengths();
+                self.clock_sweep();
+            }
+            7 => {
+                self.square_1.envelope.clock();
+                self.square_2.envelope.clock();
+                self.noise.envelope.clock();
+            }
+            _ => {}
+        }
+        self.frame_sequencer = (self.frame_sequencer + 1) % 8;
+    }
+
+    fn clock_lengths(&mut self) {
+        if self.square_1.length.clock() {
+            self.square_1.enabled = false;
+        }
+        if self.square_2.length.clock() {
+            self.square_2.enabled = false;
+        }
+        if self.wave.length.clock() {
+            self.wave.enabled = false;
+        }
+        if self.noise.length.clock() {
+            self.noise.enabled = false;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep.timer > 0 {
+            self.sweep.timer -= 1;
+        }
+        if self.sweep.timer != 0 {
+            return;
+        }
+        self.sweep.timer = if self.sweep.period == 0 { 8 } else { self.sweep.period };
+        if !self.sweep.enabled || self.sweep.period == 0 {
+            return;
+        }
+
+        let frequency = self.sweep.next_frequency();
+        if frequency > 2047 {
+            self.square_1.enabled = false;
+        } else if self.sweep.shift != 0 {
+            self.sweep.shadow_frequency = frequency;
+            self.square_1.frequency = frequency;
+            //The new frequency is checked for overflow straight away
+            if self.sweep.next_frequency() > 2047 {
+                self.square_1.enabled = false;
+            }
+        }
+    }
+
+    fn trigger_sweep(&mut self) {
+        self.sweep.shadow_frequency = self.square_1.frequency;
+        self.sweep.timer = if self.sweep.period == 0 { 8 } else { self.sweep.period };
+        self.sweep.enabled = self.sweep.period != 0 || self.sweep.shift != 0;
+        self.sweep.negate_used = false;
+        if self.sweep.shift != 0 && self.sweep.next_frequency() > 2047 {
+            self.square_1.enabled = false;
+        }
+    }
+
+    fn output_sample(&mut self) {
+        let analog = |dac_enabled: bool, value: u8| {
+            if dac_enabled { value as f32 / 7.5 - 1.0 } else { 0.0 }
+        };
+        let channels = [
+            analog(self.square_1.dac_enabled, self.square_1.output()),
+            analog(self.square_2.dac_enabled, self.square_2.output()),
+            analog(self.wave.dac_enabled, self.wave.output()),
+            analog(self.noise.dac_enabled, self.noise.output()),
+        ];
+
+        let panning = self.registers[0x15];
+        let volume = self.registers[0x14];
+        let mut left = 0.0;
+        let mut right = 0.0;
+        if self.power {
+            for (channel, value) in channels.iter().enumerate() {
+                if (panning >> (channel + 4)) & 0b1 == 1 {
+                    left += value;
+                }
+                if (panning >> channel) & 0b1 == 1 {
+                    right += value;
+                }
+            }
+        }
+        left *= (((volume >> 4) & 0x07) + 1) as f32 / 32.0;
+        right *= ((volume & 0x07) + 1) as f32 / 32.0;
+
+        //The hardware high pass filter removes the DC offset of enabled DACs
+        let charge_factor = 0.999958f32.powf((CPU_CLOCK / self.sample_rate) as f32);
+        let left_out = left - self.capacitor.0;
+        self.capacitor.0 = left - left_out * charge_factor;
+        let right_out = right - self.capacitor.1;
+        self.capacitor.1 = right - right_out * charge_factor;
+
+        //Keep at most one second of audio if nobody is listening
+        if self.samples.len() >= self.sample_rate as usize * 2 {
+            self.samples.drain(0..self.sample_rate as usize);
+        }
+        self.samples.push(left_out);
+        self.samples.push(right_out);
+    }
+
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            0xFF26 => {
+                0x70 |
+                (self.power as u8) << 7 |
+                (self.noise.enabled as u8) << 3 |
+                (self.wave.enabled as u8) << 2 |
+                (self.square_2.enabled as u8) << 1 |
+                self.square_1.enabled as u8
+            }
+            0xFF10..=0xFF25 => {
+                let index = (address - 0xFF10) as usize;
+                self.registers[index] | READ_MASKS[index]
+            }
+            0xFF30..=0xFF3F => self.wave.wave_ram[(address - 0xFF30) as usize],
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0xFF10..=0xFF25 => {}
+            0xFF26 => return self.write_power(value),
+            0xFF30..=0xFF3F => {
+                self.wave.wave_ram[(address - 0xFF30) as usize] = value;
+                return;
+            }
+            _ => return,
+        }
+
+        if !self.power {
+            //Length counters can still be loaded while powered off
+            match address {
+                0xFF11 => self.square_1.length.load(SQUARE_LENGTH, (value & 0x3F) as u16),
+                0xFF16 => self.square_2.length.load(SQUARE_LENGTH, (value & 0x3F) as u16),
+                0xFF1B => self.wave.length.load(WAVE_LENGTH, value as u16),
+                0xFF20 => self.noise.length.load(NOISE_LENGTH, (value & 0x3F) as u16),
+                _ => {}
+            }
+            return;
+        }
+
+        self.registers[(address - 0xFF10) as usize] = value;
+        let odd_step = (self.frame_sequencer & 0b1) == 1;
+        let trigger = (value & 0x80) != 0;
+        let length_enable = (value & 0x40) != 0;
+
+        match address {
+            0xFF10 => {
+                /* NR10 - Channel 1 sweep */
+                let keep_enabled = self.sweep.write(value);
+                self.square_1.enabled &= keep_enabled;
+            }
+            0xFF11 => {
+                /* NR11 - Channel 1 duty and length */
+                self.square_1.duty = value >> 6;
+                self.square_1.length.load(SQUARE_LENGTH, (value & 0x3F) as u16);
+            }
+            0xFF12 => {
+                /* NR12 - Channel 1 volume envelope */
+                self.square_1.envelope.write(value);
+                self.square_1.dac_enabled = (value & 0xF8) != 0;
+                self.square_1.enabled &= self.square_1.dac_enabled;
+            }
+            0xFF13 => {
+                /* NR13 - Channel 1 frequency low */
+                self.square_1.frequency = (self.square_1.frequency & 0x700) | value as u16;
+            }
+            0xFF14 => {
+                /* NR14 - Channel 1 trigger, length enable and frequency high */
+                self.square_1.frequency =
+                    (self.square_1.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                if !self.square_1.length.write_control(length_enable, trigger, SQUARE_LENGTH, odd_step) {
+                    self.square_1.enabled = false;
+                }
+                if trigger {
+                    self.square_1.trigger();
+                    self.trigger_sweep();
+                }
+            }
+
+            0xFF16 => {
+                /* NR21 - Channel 2 duty and length */
+                self.square_2.duty = value >> 6;
+                self.square_2.length.load(SQUARE_LENGTH, (value & 0x3F) as u16);
+            }
+            0xFF17 => {
+                /* NR22 - Channel 2 volume envelope */
+                self.square_2.envelope.write(value);
+                self.square_2.dac_enabled = (value & 0xF8) != 0;
+                self.square_2.enabled &= self.square_2.dac_enabled;
+            }
+            0xFF18 => {
+                /* NR23 - Channel 2 frequency low */
+                self.square_2.frequency = (self.square_2.frequency & 0x700) | value as u16;
+            }
+            0xFF19 => {
+                /* NR24 - Channel 2 trigger, length enable and frequency high */
+                self.square_2.frequency =
+                    (self.square_2.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                if !self.square_2.length.write_control(length_enable, trigger, SQUARE_LENGTH, odd_step) {
+                    self.square_2.enabled = false;
+                }
+                if trigger {
+                    self.square_2.trigger();
+                }
+            }
+
+            0xFF1A => {
+                /* NR30 - Channel 3 DAC power */
+                self.wave.dac_enabled = (value & 0x80) != 0;
+                self.wave.enabled &= self.wave.dac_enabled;
+            }
+            0xFF1B => {
+                /* NR31 - Channel 3 length */
+                self.wave.length.load(WAVE_LENGTH, value as u16);
+            }
+            0xFF1C => {
+                /* NR32 - Channel 3 output level */
+                self.wave.output_level = (value >> 5) & 0b11;
+            }
+            0xFF1D => {
+                /* NR33 - Channel 3 frequency low */
+                self.wave.frequency = (self.wave.frequency & 0x700) | value as u16;
+            }
+            0xFF1E => {
+                /* NR34 - Channel 3 trigger, length enable and frequency high */
+                self.wave.frequency =
+                    (self.wave.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                if !self.wave.length.write_control(length_enable, trigger, WAVE_LENGTH, odd_step) {
+                    self.wave.enabled = false;
+                }
+                if trigger {
+                    self.wave.trigger();
+                }
+            }
+
+            0xFF20 => {
+                /* NR41 - Channel 4 length */
+                self.noise.length.load(NOISE_LENGTH, (value & 0x3F) as u16);
+            }
+            0xFF21 => {
+                /* NR42 - Channel 4 volume envelope */
+                self.noise.envelope.write(value);
+                self.noise.dac_enabled = (value & 0xF8) != 0;
+                self.noise.enabled &= self.noise.dac_enabled;
+            }
+            0xFF22 => {
+                /* NR43 - Channel 4 clock shift, LFSR width and divisor */
+                self.noise.clock_shift = value >> 4;
+                self.noise.width_mode = (value & 0x08) != 0;
+                self.noise.divisor = value & 0x07;
+            }
+            0xFF23 => {
+                /* NR44 - Channel 4 trigger and length enable */
+                if !self.noise.length.write_control(length_enable, trigger, NOISE_LENGTH, odd_step) {
+                    self.noise.enabled = false;
+                }
+                if trigger {
+                    self.noise.trigger();
+                }
+            }
+
+            /* NR50 master volume and NR51 panning are read straight from the registers */
+            _ => {}
+        }
+    }
+
+    fn write_power(&mut self, value: u8) {
+        let power = (value & 0x80) != 0;
+        if self.power && !power {
+            //Powering off clears every register, length counters and wave RAM survive
+            let lengths = [
+                self.square_1.length.counter,
+                self.square_2.length.counter,
+                self.wave.length.counter,
+                self.noise.length.counter,
+            ];
+            let wave_ram = self.wave.wave_ram;
+
+            self.registers = [0; REGISTER_COUNT];
+            self.square_1 = Default::default();
+            self.sweep = Default::default();
+            self.square_2 = Default::default();
+            self.wave = Default::default();
+            self.noise = Default::default();
+
+            self.square_1.length.counter = lengths[0];
+            self.square_2.length.counter = lengths[1];
+            self.wave.length.counter = lengths[2];
+            self.noise.length.counter = lengths[3];
+            self.wave.wave_ram = wave_ram;
+        } else if !self.power && power {
+            self.frame_sequencer = 0;
+        }
+        self.power = power;
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.registers);
+        state.write_bool(self.power);
+        state.write_u8(self.frame_sequencer);
+        self.square_1.save_state(state);
+        self.sweep.save_state(state);
+        self.square_2.save_state(state);
+        self.wave.save_state(state);
+        self.noise.save_state(state);
+        state.write_u32(self.sample_cycles);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut registers = [0; REGISTER_COUNT];
+        state.read_bytes(&mut registers)?;
+        self.power = state.read_bool()?;
+        self.frame_sequencer = state.read_u8()? % 8;
+        self.square_1.load_state(state)?;
+        self.sweep.load_state(state)?;
+        self.square_2.load_state(state)?;
+        self.wave.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.sample_cycles = state.read_u32()? % CPU_CLOCK;
+
+        //Settings that only change on register writes are decoded again
+        self.registers = registers;
+        self.sweep.write(registers[0x00]);
+        self.square_1.duty = registers[0x01] >> 6;
+        self.square_1.envelope.write(registers[0x02]);
+        self.square_1.dac_enabled = (registers[0x02] & 0xF8) != 0;
+        self.square_2.duty = registers[0x06] >> 6;
+        self.square_2.envelope.write(registers[0x07]);
+        self.square_2.dac_enabled = (registers[0x07] & 0xF8) != 0;
+        self.wave.dac_enabled = (registers[0x0A] & 0x80) != 0;
+        self.wave.output_level = (registers[0x0C] >> 5) & 0b11;
+        self.noise.envelope.write(registers[0x11]);
+        self.noise.dac_enabled = (registers[0x11] & 0xF8) != 0;
+        self.noise.clock_shift = registers[0x12] >> 4;
+        self.noise.width_mode = (registers[0x12] & 0x08) != 0;
+        self.noise.divisor = registers[0x12] & 0x07;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn powered_apu() -> APU {
+        let mut apu = APU::new(DEFAULT_SAMPLE_RATE);
+        apu.write_register(0xFF26, 0x80);
+        apu
+    }
+
+    #[test]
+    fn register_read_masks() {
+        let mut apu = powered_apu();
+        for address in 0xFF10..0xFF26 {
+            apu.write_register(address, 0x00);
+        }
+        let expected = [
+            0x80, 0x3F, 0x00, 0xFF, 0xBF,
+            0xFF, 0x3F, 0x00, 0xFF, 0xBF,
+            0x7F, 0xFF, 0x9F, 0xFF, 0xBF,
+            0xFF, 0xFF, 0x00, 0x00, 0xBF,
+            0x00, 0x00, 0xF0,
+        ];
+        for (offset, value) in expected.iter().enumerate() {
+            assert_eq!(apu.read_register(0xFF10 + offset as u16), *value);
+        }
+        assert_eq!(apu.read_register(0xFF27), 0xFF);
+    }
+
+    #[test]
+    fn power_off_clears_registers() {
+        let mut apu = powered_apu();
+        apu.write_register(0xFF30, 0x12);
+        apu.write_register(0xFF24, 0x77);
+        apu.write_register(0xFF26, 0x00);
+        assert_eq!(apu.read_register(0xFF24), 0x00);
+        assert_eq!(apu.read_register(0xFF26), 0x70);
+
+        apu.write_register(0xFF24, 0x77);
+        assert_eq!(apu.read_register(0xFF24), 0x00);
+        assert_eq!(apu.read_register(0xFF30), 0x12);
+    }
+
+    #[test]
+    fn length_counter_stops_channel() {
+        let mut apu = powered_apu();
+        apu.write_register(0xFF17, 0xF0);
+        apu.write_register(0xFF16, 0x3E);
+        apu.write_register(0xFF19, 0xC0);
+        assert_eq!(apu.read_register(0xFF26) & 0b10, 0b10);
+
+        //Two clocks left, a length step happens on every other frame sequencer step
+        for _ in 0..3 {
+            apu.clock_frame_sequencer();
+        }
+        assert_eq!(apu.read_register(0xFF26) & 0b10, 0);
+    }
+
+    #[test]
+    fn dac_off_disables_channel() {
+        let mut apu = powered_apu();
+        apu.write_register(0xFF12, 0xF0);
+        apu.write_register(0xFF14, 0x80);
+        assert_eq!(apu.read_register(0xFF26) & 0b1, 0b1);
+        apu.write_register(0xFF12, 0x00);
+        assert_eq!(apu.read_register(0xFF26) & 0b1, 0);
+    }
+
+    #[test]
+    fn sweep_overflow_disables_channel() {
+        let mut apu = powered_apu();
+        apu.write_register(0xFF12, 0xF0);
+        apu.write_register(0xFF10, 0x11);
+        apu.write_register(0xFF13, 0xFF);
+        apu.write_register(0xFF14, 0x87);
+        assert_eq!(apu.read_register(0xFF26) & 0b1, 0);
+    }
+
+    #[test]
+    fn noise_lfsr_shifts() {
+        let mut apu = powered_apu();
+        apu.write_register(0xFF21, 0xF0);
+        apu.write_register(0xFF22, 0x00);
+        apu.write_register(0xFF23, 0x80);
+        apu.step(8);
+        assert_eq!(apu.noise.lfsr, 0x3FFF);
+    }
+
+    #[test]
+    fn generates_samples_at_sample_rate() {
+        let mut apu = APU::new(48000);
+        for _ in 0..(CPU_CLOCK / 16) {
+            apu.step(16);
+        }
+        assert_eq!(apu.take_samples().len(), 48000 * 2);
+        assert!(apu.take_samples().is_empty());
+    }
+}
@@ -0,0 +1,354 @@
+use std::fmt;
+
+use crate::mbc::{ROM_BANK_SIZE, RAM_BANK_SIZE};
+
+/* Cartridge header
+ * 0100-0103 entry point
+ * 0104-0133 nintendo logo
+ * 0134-0143 title, upper case ASCII padded with 0
+ * 013F-0142 manufacturer code on newer cartridges
+ * 0143      CGB flag
+ * 0144-0145 new licensee code
+ * 0146      SGB flag
+ * 0147      cartridge type
+ * 0148      ROM size
+ * 0149      RAM size
+ * 014A      destination code
+ * 014B      old licensee code
+ * 014C      mask ROM version number
+ * 014D      header checksum
+ * 014E-014F global checksum
+ */
+const TITLE_START: usize = 0x134;
+const TITLE_END: usize = 0x143;
+const MANUFACTURER_CODE_START: usize = 0x13F;
+const MANUFACTURER_CODE_END: usize = 0x142;
+const CGB_FLAG: usize = 0x143;
+const NEW_LICENSEE_CODE_START: usize = 0x144;
+const NEW_LICENSEE_CODE_END: usize = 0x145;
+const SGB_FLAG: usize = 0x146;
+const CARTRIDGE_TYPE: usize = 0x147;
+const ROM_SIZE: usize = 0x148;
+const RAM_SIZE: usize = 0x149;
+const OLD_LICENSEE_CODE: usize = 0x14B;
+const VERSION: usize = 0x14C;
+const HEADER_CHECKSUM: usize = 0x14D;
+const GLOBAL_CHECKSUM_START: usize = 0x14E;
+const GLOBAL_CHECKSUM_END: usize = 0x14F;
+
+const HEADER_END: usize = 0x14F;
+
+#[derive(Debug, PartialEq)]
+pub enum CartridgeError {
+    TooSmall(usize),
+    UnsupportedType(u8),
+    UnsupportedRomSize(u8),
+    UnsupportedRamSize(u8),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CartridgeError::TooSmall(size) => write!(
+                f, "ROM is {} bytes, too small to contain a cartridge header", size),
+            CartridgeError::UnsupportedType(code) => write!(
+                f, "Unsupported cartridge type 0x{:02X} ({})", code, cartridge_type_name(*code)),
+            CartridgeError::UnsupportedRomSize(code) => write!(
+                f, "Unsupported ROM size code 0x{:02X}", code),
+            CartridgeError::UnsupportedRamSize(code) => write!(
+                f, "Unsupported RAM size code 0x{:02X}", code),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+fn cartridge_type_name(code: u8) -> &'static str {
+    match code {
+        0x00 => "ROM ONLY",
+        0x01 => "MBC1",
+        0x02 => "MBC1+RAM",
+        0x03 => "MBC1+RAM+BATTERY",
+        0x05 => "MBC2",
+        0x06 => "MBC2+BATTERY",
+        0x08 => "ROM+RAM",
+        0x09 => "ROM+RAM+BATTERY",
+        0x0B => "MMM01",
+        0x0C => "MMM01+RAM",
+        0x0D => "MMM01+RAM+BATTERY",
+        0x0F => "MBC3+TIMER+BATTERY",
+        0x10 => "MBC3+TIMER+RAM+BATTERY",
+        0x11 => "MBC3",
+        0x12 => "MBC3+RAM",
+        0x13 => "MBC3+RAM+BATTERY",
+        0x19 => "MBC5",
+        0x1A => "MBC5+RAM",
+        0x1B => "MBC5+RAM+BATTERY",
+        0x1C => "MBC5+RUMBLE",
+        0x1D => "MBC5+RUMBLE+RAM",
+        0x1E => "MBC5+RUMBLE+RAM+BATTERY",
+        0x20 => "MBC6",
+        0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+        0xFC => "POCKET CAMERA",
+        0xFD => "BANDAI TAMA5",
+        0xFE => "HuC3",
+        0xFF => "HuC1+RAM+BATTERY",
+        _ => "unknown",
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mapper {
+    RomOnly,
+    MBC1,
+    MBC3,
+    MBC5,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CartridgeType {
+    pub code: u8,
+    pub mapper: Mapper,
+    pub ram: bool,
+    pub battery: bool,
+    pub timer: bool,
+    pub rumble: bool,
+}
+
+impl CartridgeType {
+    pub fn from_byte(code: u8) -> Result<CartridgeType, CartridgeError> {
+        let (mapper, ram, battery, timer, rumble) = match code {
+            0x00 => (Mapper::RomOnly, false, false, false, false),
+            0x01 => (Mapper::MBC1, false, false, false, false),
+            0x02 => (Mapper::MBC1, true, false, false, false),
+            0x03 => (Mapper::MBC1, true, true, false, false),
+            0x08 => (Mapper::RomOnly, true, false, false, false),
+            0x09 => (Mapper::RomOnly, true, true, false, false),
+            0x0F => (Mapper::MBC3, false, true, true, false),
+            0x10 => (Mapper::MBC3, true, true, true, false),
+            0x11 => (Mapper::MBC3, false, false, false, false),
+            0x12 => (Mapper::MBC3, true, false, false, false),
+            0x13 => (Mapper::MBC3, true, true, false, false),
+            0x19 => (Mapper::MBC5, false, false, false, false),
+            0x1A => (Mapper::MBC5, true, false, false, false),
+            0x1B => (Mapper::MBC5, true, true, false, false),
+            0x1C => (Mapper::MBC5, false, false, false, true),
+            0x1D => (Mapper::MBC5, true, false, false, true),
+            0x1E => (Mapper::MBC5, true, true, false, true),
+            _ => return Err(CartridgeError::UnsupportedType(code)),
+        };
+
+        Ok(CartridgeType { code, mapper, ram, battery, timer, rumble })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CgbSupport {
+    None,
+    Supported,
+    Required,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Licensee {
+    Old(u8),
+    New([u8; 2]),
+}
+
+pub struct Cartridge {
+    pub title: String,
+    pub manufacturer_code: Option<String>,
+    pub cgb_support: CgbSupport,
+    pub sgb_support: bool,
+    pub cartridge_type: CartridgeType,
+    pub rom_size_code: u8,
+    pub ram_size_code: u8,
+    pub licensee: Licensee,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+
+    pub data: Vec<u8>,
+}
+
+impl Cartridge {
+    pub fn new(data: Vec<u8>) -> Result<Cartridge, CartridgeError> {
+        if data.len() <= HEADER_END {
+            return Err(CartridgeError::TooSmall(data.len()));
+        }
+
+        let cgb_support = match data[CGB_FLAG] {
+            0x80 => CgbSupport::Supported,
+            0xC0 => CgbSupport::Required,
+            _ => CgbSupport::None,
+        };
+
+        //On CGB aware cartridges the end of the title area is reused for the
+        //manufacturer code and the CGB flag
+        let (title_end, manufacturer_code) = if cgb_support == CgbSupport::None {
+            (TITLE_END, None)
+        } else {
+            let code = &data[MANUFACTURER_CODE_START..=MANUFACTURER_CODE_END];
+            let manufacturer_code = if code.iter().all(|c| c.is_ascii_uppercase()) {
+                Some(String::from_utf8_lossy(code).into_owned())
+            } else {
+                None
+            };
+            (MANUFACTURER_CODE_START - 1, manufacturer_code)
+        };
+
+        let title = data[TITLE_START..=title_end].iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as char)
+            .collect::<String>();
+
+        let licensee = if data[OLD_LICENSEE_CODE] == 0x33 {
+            let mut code = [0; 2];
+            code.copy_from_slice(&data[NEW_LICENSEE_CODE_START..=NEW_LICENSEE_CODE_END]);
+            Licensee::New(code)
+        } else {
+            Licensee::Old(data[OLD_LICENSEE_CODE])
+        };
+
+        let cartridge_type = CartridgeType::from_byte(data[CARTRIDGE_TYPE])?;
+        let rom_size_code = data[ROM_SIZE];
+        if rom_size_code > 0x08 {
+            return Err(CartridgeError::UnsupportedRomSize(rom_size_code));
+        }
+        let ram_size_code = data[RAM_SIZE];
+        if ram_size_code > 0x05 {
+            return Err(CartridgeError::UnsupportedRamSize(ram_size_code));
+        }
+
+        Ok(Cartridge {
+            title,
+            manufacturer_code,
+            cgb_support,
+            sgb_support: data[SGB_FLAG] == 0x03,
+            cartridge_type,
+            rom_size_code,
+            ram_size_code,
+            licensee,
+            version: data[VERSION],
+            header_checksum: data[HEADER_CHECKSUM],
+            global_checksum:
+                (data[GLOBAL_CHECKSUM_START] as u16) << 8 | data[GLOBAL_CHECKSUM_END] as u16,
+            data,
+        })
+    }
+
+    pub fn empty() -> Cartridge {
+        Cartridge::new(vec![0; 0x10000]).unwrap()
+    }
+
+    pub fn rom_size(&self) -> usize {
+        (ROM_BANK_SIZE * 2) << self.rom_size_code
+    }
+
+    pub fn ram_size(&self) -> usize {
+        match self.ram_size_code {
+            0x01 => 0x800,
+            0x02 => RAM_BANK_SIZE,
+            0x03 => RAM_BANK_SIZE * 4,
+            0x04 => RAM_BANK_SIZE * 16,
+            0x05 => RAM_BANK_SIZE * 8,
+            _ => 0,
+        }
+    }
+
+    pub fn header_checksum_valid(&self) -> bool {
+        let checksum = self.data[TITLE_START..=VERSION].iter()
+            .fold(0u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1));
+        checksum == self.header_checksum
+    }
+
+    pub fn global_checksum_valid(&self) -> bool {
+        let checksum = self.data.iter()
+            .enumerate()
+            .filter(|(i, _)| *i != GLOBAL_CHECKSUM_START && *i != GLOBAL_CHECKSUM_END)
+            .fold(0u16, |sum, (_, byte)| sum.wrapping_add(*byte as u16));
+        checksum == self.global_checksum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom_with_header(title: &str, cartridge_type: u8) -> Vec<u8> {
+        let mut rom = vec![0; ROM_BANK_SIZE * 2];
+        rom[TITLE_START..TITLE_START + title.len()].copy_from_slice(title.as_bytes());
+        rom[CARTRIDGE_TYPE] = cartridge_type;
+        rom
+    }
+
+    #[test]
+    fn parses_title_and_type() {
+        let mut rom = rom_with_header("TETRIS", 0x03);
+        rom[RAM_SIZE] = 0x03;
+        rom[VERSION] = 0x01;
+        let cartridge = Cartridge::new(rom).unwrap();
+        assert_eq!(cartridge.title, "TETRIS");
+        assert_eq!(cartridge.cartridge_type.mapper, Mapper::MBC1);
+        assert_eq!(cartridge.cartridge_type.battery, true);
+        assert_eq!(cartridge.ram_size(), RAM_BANK_SIZE * 4);
+        assert_eq!(cartridge.rom_size(), ROM_BANK_SIZE * 2);
+        assert_eq!(cartridge.version, 0x01);
+        assert_eq!(cartridge.cgb_support, CgbSupport::None);
+        assert_eq!(cartridge.manufacturer_code, None);
+    }
+
+    #[test]
+    fn cgb_cartridge_has_short_title_and_manufacturer_code() {
+        let mut rom = rom_with_header("POKEMON_GLDAAUE", 0x10);
+        rom[CGB_FLAG] = 0x80;
+        let cartridge = Cartridge::new(rom).unwrap();
+        assert_eq!(cartridge.title, "POKEMON_GLD");
+        assert_eq!(cartridge.manufacturer_code, Some("AAUE".to_string()));
+        assert_eq!(cartridge.cgb_support, CgbSupport::Supported);
+        assert_eq!(cartridge.cartridge_type.timer, true);
+    }
+
+    #[test]
+    fn new_licensee_code() {
+        let mut rom = rom_with_header("GAME", 0x00);
+        rom[OLD_LICENSEE_CODE] = 0x33;
+        rom[NEW_LICENSEE_CODE_START] = b'0';
+        rom[NEW_LICENSEE_CODE_END] = b'1';
+        let cartridge = Cartridge::new(rom).unwrap();
+        assert_eq!(cartridge.licensee, Licensee::New([b'0', b'1']));
+    }
+
+    #[test]
+    fn unsupported_type_is_an_error() {
+        let rom = rom_with_header("GAME", 0x05);
+        let error = Cartridge::new(rom).err().unwrap();
+        assert_eq!(error, CartridgeError::UnsupportedType(0x05));
+        assert_eq!(format!("{}", error), "Unsupported cartridge type 0x05 (MBC2)");
+    }
+
+    #[test]
+    fn too_small_is_an_error() {
+        assert_eq!(Cartridge::new(vec![0; 0x100]).err(), Some(CartridgeError::TooSmall(0x100)));
+    }
+
+    #[test]
+    fn checksums() {
+        let mut rom = rom_with_header("GAME", 0x00);
+        let header_checksum = rom[TITLE_START..=VERSION].iter()
+            .fold(0u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1));
+        rom[HEADER_CHECKSUM] = header_checksum;
+        let global_checksum = rom.iter().fold(0u16, |sum, byte| sum.wrapping_add(*byte as u16));
+        rom[GLOBAL_CHECKSUM_START] = (global_checksum >> 8) as u8;
+        rom[GLOBAL_CHECKSUM_END] = global_checksum as u8;
+
+        let cartridge = Cartridge::new(rom).unwrap();
+        assert_eq!(cartridge.header_checksum_valid(), true);
+        assert_eq!(cartridge.global_checksum_valid(), true);
+
+        let mut rom = cartridge.data.clone();
+        rom[HEADER_CHECKSUM] ^= 0xFF;
+        let cartridge = Cartridge::new(rom).unwrap();
+        assert_eq!(cartridge.header_checksum_valid(), false);
+    }
+}
@@ -1037,7 +1037,7 @@ impl Instruction {
     pub fn byte_length(&self) -> u8 {
         match self {
             Instruction::NOP => 1,
-            Instruction::HALT => 2,
+            Instruction::HALT => 1,
             Instruction::STOP => 2,
 
             Instruction::DI => 1,
@@ -4,20 +4,22 @@ pub mod registers;
 
 use self::instruction::*;
 use self::registers::Registers;
+use crate::cartridge::Cartridge;
+use crate::error::EmulatorError;
 use crate::memory_bus::MemoryBus;
-use crate::interrupts::{InterruptLocation};
-
-#[derive(Debug, PartialEq)]
-enum InterruptState {
-    Enabled,
-    Disabled,
-    Enabling,
-    Disabling,
-}
+use crate::state::{StateError, StateReader, StateWriter};
 
 pub struct CPU {
     is_halted: bool,
-    interrupt_state: InterruptState,
+    is_stopped: bool,
+    is_locked: bool, //an undefined opcode was executed, nothing but a reset gets the CPU going
+    halt_bug: bool, //the next opcode fetch doesn't move PC
+    cycles: u16, //T-cycles the bus has been stepped by during the current instruction
+    /* IME, EI only sets it after the instruction that follows EI has run,
+     * DI and RETI take effect straight away
+     */
+    interrupt_enabled: bool,
+    interrupt_enable_pending: bool,
     pub pc: u16,
     pub sp: u16,
     pub registers: Registers,
@@ -26,148 +28,254 @@ pub struct CPU {
 }
 
 impl CPU {
-    pub fn new(boot_room: Option<Vec<u8>>, game_rom: Vec<u8>) -> CPU {
+    pub fn new(boot_room: Option<Vec<u8>>, cartridge: Cartridge) -> CPU {
         CPU {
             is_halted: false,
-            interrupt_state: InterruptState::Enabled,
-            bus: MemoryBus::new(boot_room, game_rom),
+            is_stopped: false,
+            is_locked: false,
+            halt_bug: false,
+            cycles: 0,
+            interrupt_enabled: true,
+            interrupt_enable_pending: false,
+            bus: MemoryBus::new(boot_room, cartridge),
             pc: 0,
             sp: 0,
             registers: Registers::new()
         }
     }
 
+    /* Snapshot of the whole machine, the cartridge ROM itself is not included */
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.write_u16(self.registers.get_af());
+        state.write_u16(self.registers.get_bc());
+        state.write_u16(self.registers.get_de());
+        state.write_u16(self.registers.get_hl());
+        state.write_u16(self.pc);
+        state.write_u16(self.sp);
+        state.write_bool(self.is_halted);
+        state.write_bool(self.is_stopped);
+        state.write_bool(self.is_locked);
+        state.write_bool(self.halt_bug);
+        state.write_bool(self.interrupt_enabled);
+        state.write_bool(self.interrupt_enable_pending);
+        self.bus.save_state(&mut state);
+        state.finish()
+    }
+
+    /* A state that fails to load leaves the machine as it was, the components are
+     * restored piecewise so on any error everything is put back from a snapshot
+     */
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let snapshot = self.save_state();
+        let result = self.restore_state(data);
+        if result.is_err() {
+            self.restore_state(&snapshot).expect("Snapshot of the running machine failed to load");
+        }
+        result
+    }
+
+    fn restore_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data)?;
+        self.registers.set_af(state.read_u16()?);
+        self.registers.set_bc(state.read_u16()?);
+        self.registers.set_de(state.read_u16()?);
+        self.registers.set_hl(state.read_u16()?);
+        self.pc = state.read_u16()?;
+        self.sp = state.read_u16()?;
+        self.is_halted = state.read_bool()?;
+        self.is_stopped = state.read_bool()?;
+        self.is_locked = state.read_bool()?;
+        self.halt_bug = state.read_bool()?;
+        self.interrupt_enabled = state.read_bool()?;
+        self.interrupt_enable_pending = state.read_bool()?;
+        self.bus.load_state(&mut state)
+    }
+
     pub fn debug_output(&self) {
         let mut instruction_byte = self.bus.read_byte(self.pc);
         let mut output = format!("PC:0x{:04X}: ", self.pc);
         let prefixed = instruction_byte == 0xCB;
         if prefixed {
             output += "0xCB ";
-            instruction_byte = self.read_next_byte();
+            instruction_byte = self.bus.read_byte(self.pc.wrapping_add(1));
         }
         if let Some(instruction) = Instruction::from_byte(instruction_byte, prefixed) {
 
             output += format!("{:?} (0x{:02X})", instruction, instruction_byte).as_str();
             let instruction_length = instruction.byte_length();
             if instruction_length == 2{
-                output += format!(" {} ", self.read_next_byte()).as_str();
+                output += format!(" {} ", self.bus.read_byte(self.pc.wrapping_add(1))).as_str();
             }
             println!("{}\t {:?}, sp: 0x{:X}",
                     output, self.registers, self.sp);
         }
     }
 
+    /* Like try_step, an undefined opcode just leaves the CPU locked up */
     pub fn step(&mut self) -> u16 {
-        let mut instruction_byte = self.bus.read_byte(self.pc);
+        self.try_step().unwrap_or(4)
+    }
+
+    /* Runs one instruction, returns the T-cycles it took.
+     * Undefined opcodes hang the DMG, the CPU stops fetching and interrupts can't
+     * wake it, while the rest of the machine keeps running. The error is returned once.
+     */
+    pub fn try_step(&mut self) -> Result<u16, EmulatorError> {
+        if self.is_locked {
+            self.bus.step(4);
+            return Ok(4);
+        }
+        if self.is_halted || self.is_stopped {
+            return Ok(self.step_halted());
+        }
+
+        self.cycles = 0;
+        let mut instruction_byte = self.read_cycle(self.pc);
+        if self.halt_bug {
+            //The byte after HALT is read again as the first operand or the next opcode
+            self.halt_bug = false;
+            self.pc = self.pc.wrapping_sub(1);
+        }
         let prefixed = instruction_byte == 0xCB;
         if prefixed {
-            instruction_byte = self.read_next_byte();
+            instruction_byte = self.read_cycle(self.pc.wrapping_add(1));
         }
 
-
-        let (next_pc, mut cycles) = if let Some(instruction) = Instruction::from_byte(instruction_byte, prefixed)
+        //An EI before this instruction takes effect once it's done, unless it was a DI
+        let enable_interrupts = self.interrupt_enable_pending;
+        let (next_pc, cycles) = if let Some(instruction) = Instruction::from_byte(instruction_byte, prefixed)
         {
-            let (pc, cycles) = self.execute(instruction);
-
-            //FIXME: this should be simpler than this
-            if instruction_byte != 0xF3 && instruction_byte != 0xFB {
-                self.interrupt_state = match self.interrupt_state {
-                    InterruptState::Enabling => InterruptState::Enabled,
-                    InterruptState::Disabling => InterruptState::Disabled,
-                    InterruptState::Enabled => InterruptState::Enabled,
-                    InterruptState::Disabled => InterruptState::Disabled,
-                };
-            }
-            (pc, cycles)
+            self.execute(instruction)
         } else {
-            let description = format!(
-                "0x{}{:x}",
-                if prefixed { "cb" } else { "" },
-                instruction_byte
-            );
-            panic!("Unkown instruction found for: {}", description);
+            self.is_locked = true;
+            return Err(EmulatorError::UndefinedOpcode(instruction_byte, self.pc));
         };
 
-        self.bus.step(cycles);
-        if self.bus.interrupted() {
-            self.is_halted = false;
+        //Whatever is left after the last memory access is internal work
+        while self.cycles < cycles {
+            self.tick();
         }
-        if !self.is_halted {
-            self.pc = next_pc; //By not increasing PC, we are essentially spinlocking here until the interrupt occurs
+        debug_assert_eq!(self.cycles, cycles, "bus stepped more than instruction 0x{:02X} takes", instruction_byte);
+        let cycles = self.cycles;
+        self.pc = next_pc;
+        if enable_interrupts && self.interrupt_enable_pending {
+            self.interrupt_enable_pending = false;
+            self.interrupt_enabled = true;
         }
 
-        let mut interrupted = false;
-        if self.interrupt_state == InterruptState::Enabled {
+        Ok(cycles + self.handle_interrupts())
+    }
 
-            if self.bus.interrupts_enabled.vertical_blank
-                && self.bus.interrupt_flags.vertical_blank {
-                //println!("VBlank interrupt");
-                interrupted = true;
-                self.bus.interrupt_flags.vertical_blank = false;
-                self.interrupt(InterruptLocation::VBlank);
+    /* No instructions are fetched while halted, the rest of the machine keeps running.
+     * HALT ends on any enabled and requested interrupt even with IME off.
+     * STOP stops the clock so nothing runs at all until a joypad line goes low.
+     */
+    fn step_halted(&mut self) -> u16 {
+        if self.is_stopped {
+            if self.bus.joypad.pressed() {
+                self.is_stopped = false;
             }
-            if self.bus.interrupts_enabled.lcd_c
-                && self.bus.interrupt_flags.lcd_c {
-                println!("LCD interrupt");
-                interrupted = true;
-                self.bus.interrupt_flags.lcd_c = false;
-                self.interrupt(InterruptLocation::LCD);
-            }
-            if self.bus.interrupts_enabled.timer
-                && self.bus.interrupt_flags.timer {
-                println!("timer interrupt");
-                interrupted = true;
-                self.bus.interrupt_flags.timer = false;
-                self.interrupt(InterruptLocation::Timer);
-            }
-            if self.bus.interrupts_enabled.serial_transfer
-                && self.bus.interrupt_flags.serial_transfer {
-                println!("serial interrupt");
-                interrupted = true;
-                self.bus.interrupt_flags.serial_transfer = false;
-                self.interrupt(InterruptLocation::Serial);
-            }
-            if self.bus.interrupts_enabled.joypad
-                && self.bus.interrupt_flags.joypad {
-                println!("joypad interrupt");
-                interrupted = true;
-                self.bus.interrupt_flags.joypad = false;
-                self.interrupt(InterruptLocation::Joypad);
+            return 4;
+        }
+
+        self.bus.step(4);
+        if self.bus.interrupted() {
+            self.is_halted = false;
+        }
+        if self.is_halted {
+            return 4;
+        }
+        4 + self.handle_interrupts()
+    }
+
+    /* STOP is followed by a byte that gets skipped, returns the next PC.
+     * With a button already held STOP can't wait for one, it becomes a 1 byte NOP
+     * when an interrupt is pending and acts like HALT otherwise.
+     * A CGB with a speed switch armed in KEY1 would switch speed here instead.
+     */
+    fn stop(&mut self) -> u16 {
+        if self.bus.joypad.pressed() {
+            if self.bus.interrupted() {
+                return self.pc.wrapping_add(1);
             }
+            self.is_halted = true;
+            return self.pc.wrapping_add(2);
+        }
+
+        self.bus.reset_divider();
+        self.is_stopped = true;
+        self.pc.wrapping_add(2)
+    }
+
+    /* Interrupt dispatch, 5 M-cycles: two waits, PC pushed high byte first, then the jump.
+     * Which interrupt is serviced is decided after the high byte push, so when that push
+     * lands on IE and clears the pending bits nothing is serviced and PC becomes 0x0000.
+     */
+    fn handle_interrupts(&mut self) -> u16 {
+        if !self.interrupt_enabled || !self.bus.interrupted() {
+            return 0;
         }
-        if interrupted {
-            cycles += 12;
+        self.interrupt_enabled = false;
+        if self.halt_bug {
+            //EI; HALT with an interrupt pending, the interrupt returns to the HALT
+            self.halt_bug = false;
+            self.pc = self.pc.wrapping_sub(1);
         }
+        self.tick();
+        self.tick();
+
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_cycle(self.sp, (self.pc >> 8) as u8);
+        let location = self.bus.take_interrupt();
 
-        cycles
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_cycle(self.sp, self.pc as u8);
+
+        self.pc = location.map_or(0x0000, |location| location as u16);
+        self.tick();
+        20
     }
 
-    fn read_byte_at_hl(&self) -> u8 {
-        self.bus.read_byte(self.registers.get_hl())
+    /* Every memory access takes an M-cycle, the rest of the machine is stepped
+     * before the access so timers, DMA and the PPU see it when it really happens
+     */
+    fn tick(&mut self) {
+        self.bus.step(4);
+        self.cycles += 4;
     }
 
-    fn read_next_byte(&self) -> u8 {
-        self.bus.read_byte(self.pc + 1)
+    fn read_cycle(&mut self, address: u16) -> u8 {
+        self.tick();
+        self.bus.read_byte(address)
     }
 
-    fn read_next_word(&self) -> u16 {
-        ((self.bus.read_byte(self.pc + 2) as u16) << 8) | (self.bus.read_byte(self.pc + 1) as u16)
+    fn write_cycle(&mut self, address: u16, byte: u8) {
+        self.tick();
+        self.bus.write_byte(address, byte);
     }
 
-    fn write_byte_at_hl(&mut self, value: u8) {
-        self.bus.write_byte(self.registers.get_hl(), value);
+    fn read_byte_at_hl(&mut self) -> u8 {
+        self.read_cycle(self.registers.get_hl())
+    }
+
+    fn read_next_byte(&mut self) -> u8 {
+        self.read_cycle(self.pc.wrapping_add(1))
     }
 
-    fn interrupt(&mut self, location: InterruptLocation) {
-        self.interrupt_state = InterruptState::Disabled;
-        self.push(self.pc);
-        self.pc = location as u16;
-        self.bus.step(12);
+    fn read_next_word(&mut self) -> u16 {
+        let lsb = self.read_cycle(self.pc.wrapping_add(1)) as u16;
+        let msb = self.read_cycle(self.pc.wrapping_add(2)) as u16;
+        (msb << 8) | lsb
+    }
+
+    fn write_byte_at_hl(&mut self, value: u8) {
+        self.write_cycle(self.registers.get_hl(), value);
     }
 
     fn jump(&mut self, should_jump: bool) -> (u16, u16) {
+        let addr = self.read_next_word();
         if should_jump {
-            let addr = self.read_next_word();
             (addr, 16)
         } else {
             (self.pc.wrapping_add(3), 12)
@@ -175,14 +283,15 @@ impl CPU {
     }
 
     fn restart(&mut self, address: RestartOffset) -> u16 {
+        self.tick();
         self.push(self.pc.wrapping_add(1));
         address.into()
     }
 
     fn jump_relative(&mut self, should_jump: bool) -> (u16, u16) {
         let next_pc = self.pc.wrapping_add(2);
+        let relative_offset = self.read_next_byte() as i8;
         if should_jump {
-            let relative_offset = self.read_next_byte() as i8;
             let pc = if relative_offset >= 0 {
                 next_pc.wrapping_add(relative_offset as u16)
             } else {
@@ -197,24 +306,26 @@ impl CPU {
 
     fn push(&mut self, value: u16) {
         self.sp = self.sp.wrapping_sub(1);
-        self.bus.write_byte(self.sp, ((value & 0xFF00) >> 8) as u8);
+        self.write_cycle(self.sp, ((value & 0xFF00) >> 8) as u8);
         self.sp = self.sp.wrapping_sub(1);
-        self.bus.write_byte(self.sp, (value & 0xFF) as u8);
+        self.write_cycle(self.sp, (value & 0xFF) as u8);
     }
 
     fn pop(&mut self) -> u16 {
-        let lsb = self.bus.read_byte(self.sp) as u16;
+        let lsb = self.read_cycle(self.sp) as u16;
         self.sp = self.sp.wrapping_add(1);
-        let msb = self.bus.read_byte(self.sp) as u16;
+        let msb = self.read_cycle(self.sp) as u16;
         self.sp = self.sp.wrapping_add(1);
         (msb << 8) | lsb
     }
 
     fn call(&mut self, should_jump: bool) -> (u16, u16) {
         let next_pc = self.pc.wrapping_add(3);
+        let address = self.read_next_word();
         if should_jump {
+            self.tick();
             self.push(next_pc);
-            (self.read_next_word(), 24)
+            (address, 24)
         } else {
             (next_pc, 12)
         }
@@ -232,24 +343,31 @@ impl CPU {
         match instruction {
             Instruction::NOP => (self.pc.wrapping_add(1), 4),
             Instruction::HALT => {
-                self.is_halted = true;
-                (self.pc.wrapping_add(1), 4)
-            }
-            Instruction::STOP => {
-                self.is_halted = true; //FIXME: perhaps this should have its own state?
+                //With IME off and an interrupt already pending HALT doesn't halt,
+                //instead the DMG fails to move PC past the next byte
+                if !self.interrupt_enabled && self.bus.interrupted() {
+                    self.halt_bug = true;
+                } else {
+                    self.is_halted = true;
+                }
                 (self.pc.wrapping_add(1), 4)
             }
+            Instruction::STOP => (self.stop(), 4),
             Instruction::EI => {
-                self.interrupt_state = InterruptState::Enabling;
+                if !self.interrupt_enabled {
+                    self.interrupt_enable_pending = true;
+                }
                 (self.pc.wrapping_add(1), 4)
             }
             Instruction::DI => {
-                self.interrupt_state = InterruptState::Disabling;
+                self.interrupt_enabled = false;
+                self.interrupt_enable_pending = false;
                 (self.pc.wrapping_add(1), 4)
             }
             Instruction::RETI => {
                 let pc = self.pop();
-                self.interrupt_state = InterruptState::Enabled;
+                self.interrupt_enabled = true;
+                self.interrupt_enable_pending = false;
                 (pc, 16)
             }
             Instruction::RST(offset) => (self.restart(offset), 16),
@@ -271,6 +389,7 @@ impl CPU {
                     StackTarget::DE => self.registers.get_de(),
                     StackTarget::HL => self.registers.get_hl(),
                 };
+                self.tick();
                 self.push(value);
                 (self.pc.wrapping_add(1), 16)
             }
@@ -294,6 +413,10 @@ impl CPU {
                     JumpTest::NotCarry => !self.registers.f.carry,
                     JumpTest::Always => true,
                 };
+                if test != JumpTest::Always {
+                    //The condition is checked in its own M-cycle
+                    self.tick();
+                }
                 let next_pc = self.return_(jump_condition);
 
                 let cycles = if jump_condition && test == JumpTest::Always {
@@ -326,9 +449,8 @@ impl CPU {
                 LoadType::IndirectFromSP => {
                     let address = self.read_next_word();
                     let sp = self.sp;
-                    self.bus.write_byte(address, (sp & 0xFF) as u8);
-                    self.bus
-                        .write_byte(address.wrapping_add(1), ((sp & 0xFF00) >> 8) as u8);
+                    self.write_cycle(address, (sp & 0xFF) as u8);
+                    self.write_cycle(address.wrapping_add(1), ((sp & 0xFF00) >> 8) as u8);
                     (self.pc.wrapping_add(3), 20)
                 }
                 LoadType::Byte(target, source) => {
@@ -354,22 +476,31 @@ impl CPU {
                         LoadByteTarget::HLI => self.write_byte_at_hl(source_value),
                     };
 
-                    match source {
-                        LoadByteSource::D8 => (self.pc.wrapping_add(2), 8),
-                        LoadByteSource::HLI => (self.pc.wrapping_add(1), 8),
-                        _ => (self.pc.wrapping_add(1), 4),
+                    let (length, cycles) = match source {
+                        LoadByteSource::D8 => (2, 8),
+                        LoadByteSource::HLI => (1, 8),
+                        _ => (1, 4),
+                    };
+                    //Storing to (HL) takes its own M-cycle
+                    if target == LoadByteTarget::HLI {
+                        (self.pc.wrapping_add(length), cycles + 4)
+                    } else {
+                        (self.pc.wrapping_add(length), cycles)
                     }
                 }
                 LoadType::Word(target) => {
                     match target {
                         LoadWordTarget::BC => {
-                            self.registers.set_bc(self.read_next_word());
+                            let value = self.read_next_word();
+                            self.registers.set_bc(value);
                         }
                         LoadWordTarget::DE => {
-                            self.registers.set_de(self.read_next_word());
+                            let value = self.read_next_word();
+                            self.registers.set_de(value);
                         }
                         LoadWordTarget::HL => {
-                            self.registers.set_hl(self.read_next_word());
+                            let value = self.read_next_word();
+                            self.registers.set_hl(value);
                         }
                         LoadWordTarget::SP => {
                             self.sp = self.read_next_word();
@@ -379,29 +510,24 @@ impl CPU {
                 }
                 LoadType::IndirectFromA(target) => {
                     match target {
-                        Indirect::BC => self
-                            .bus
-                            .write_byte(self.registers.get_bc(), self.registers.a),
-                        Indirect::DE => self
-                            .bus
-                            .write_byte(self.registers.get_de(), self.registers.a),
+                        Indirect::BC => self.write_cycle(self.registers.get_bc(), self.registers.a),
+                        Indirect::DE => self.write_cycle(self.registers.get_de(), self.registers.a),
                         Indirect::HLPlus => {
                             let hl = self.registers.get_hl();
                             self.registers.set_hl(hl.wrapping_add(1));
-                            self.bus.write_byte(hl, self.registers.a);
+                            self.write_cycle(hl, self.registers.a);
                         }
                         Indirect::HLMinus => {
                             let hl = self.registers.get_hl();
                             self.registers.set_hl(hl.wrapping_sub(1));
-                            self.bus.write_byte(hl, self.registers.a);
+                            self.write_cycle(hl, self.registers.a);
                         }
                         Indirect::Word => {
-                            self.bus.write_byte(
-                                self.read_next_word(), self.registers.a)
+                            let address = self.read_next_word();
+                            self.write_cycle(address, self.registers.a)
                         }
                         Indirect::LastByte => {
-                            self.bus
-                                .write_byte(0xFF00 + self.registers.c as u16, self.registers.a);
+                            self.write_cycle(0xFF00 + self.registers.c as u16, self.registers.a);
                         }
                     }
                     match target {
@@ -412,27 +538,27 @@ impl CPU {
                 LoadType::AFromIndirect(target) => {
                     match target {
                         Indirect::BC => {
-                            self.registers.a = self.bus.read_byte(self.registers.get_bc())
+                            self.registers.a = self.read_cycle(self.registers.get_bc())
                         }
                         Indirect::DE => {
-                            self.registers.a = self.bus.read_byte(self.registers.get_de())
+                            self.registers.a = self.read_cycle(self.registers.get_de())
                         }
                         Indirect::HLPlus => {
                             let hl = self.registers.get_hl();
                             self.registers.set_hl(hl.wrapping_add(1));
-                            self.registers.a = self.bus.read_byte(hl);
+                            self.registers.a = self.read_cycle(hl);
                         }
                         Indirect::HLMinus => {
                             let hl = self.registers.get_hl();
                             self.registers.set_hl(hl.wrapping_sub(1));
-                            self.registers.a = self.bus.read_byte(hl);
+                            self.registers.a = self.read_cycle(hl);
                         }
                         Indirect::Word => {
-                            self.registers.a =
-                                self.bus.read_byte(self.read_next_word())
+                            let address = self.read_next_word();
+                            self.registers.a = self.read_cycle(address)
                         }
                         Indirect::LastByte => {
-                            self.registers.a = self.bus.read_byte(0xFF00 + self.registers.c as u16);
+                            self.registers.a = self.read_cycle(0xFF00 + self.registers.c as u16);
                         }
                     }
                     match target {
@@ -443,13 +569,13 @@ impl CPU {
                 LoadType::ByteAddressFromA => {
                     let address_offset = self.read_next_byte() as u16;
                     let address = 0xFF00 + address_offset;
-                    self.bus.write_byte(address, self.registers.a);
+                    self.write_cycle(address, self.registers.a);
                     (self.pc.wrapping_add(2), 12)
                 }
                 LoadType::AFromByteAddress => {
                     let address_offset = self.read_next_byte() as u16;
                     let address = 0xFF00 + address_offset;
-                    self.registers.a = self.bus.read_byte(address);
+                    self.registers.a = self.read_cycle(address);
                     (self.pc.wrapping_add(2), 12)
                 }
             },
@@ -501,11 +627,12 @@ impl CPU {
                         self.registers.a = self.add_with_carry(self.registers.l);
                     }
                     ArithmeticTarget::D8 => {
-                        self.registers.a = self.add_with_carry(self.read_next_byte());
+                        let value = self.read_next_byte();
+                        self.registers.a = self.add_with_carry(value);
                     }
                     ArithmeticTarget::HLI => {
-                        self.registers.a =
-                            self.add_with_carry(self.read_byte_at_hl());
+                        let value = self.read_byte_at_hl();
+                        self.registers.a = self.add_with_carry(value);
                     }
                 }
                 if register == ArithmeticTarget::D8 {
@@ -540,10 +667,12 @@ impl CPU {
                         self.registers.a = self.add_without_carry(self.registers.l);
                     }
                     ArithmeticTarget::D8 => {
-                        self.registers.a = self.add_without_carry(self.read_next_byte());
+                        let value = self.read_next_byte();
+                        self.registers.a = self.add_without_carry(value);
                     }
                     ArithmeticTarget::HLI => {
-                        self.registers.a = self.add_without_carry(self.read_byte_at_hl());
+                        let value = self.read_byte_at_hl();
+                        self.registers.a = self.add_without_carry(value);
                     }
                 }
                 if register == ArithmeticTarget::D8 {
@@ -605,10 +734,12 @@ impl CPU {
                         self.registers.a = self.sub_without_carry(self.registers.l);
                     }
                     ArithmeticTarget::D8 => {
-                        self.registers.a = self.sub_without_carry(self.read_next_byte());
+                        let value = self.read_next_byte();
+                        self.registers.a = self.sub_without_carry(value);
                     }
                     ArithmeticTarget::HLI => {
-                        self.registers.a = self.sub_without_carry(self.read_byte_at_hl());
+                        let value = self.read_byte_at_hl();
+                        self.registers.a = self.sub_without_carry(value);
                     }
                 }
                 if register == ArithmeticTarget::D8 {
@@ -643,11 +774,12 @@ impl CPU {
                         self.registers.a = self.sub_with_carry(self.registers.l);
                     }
                     ArithmeticTarget::D8 => {
-                        self.registers.a = self.sub_with_carry(self.read_next_byte());
+                        let value = self.read_next_byte();
+                        self.registers.a = self.sub_with_carry(value);
                     }
                     ArithmeticTarget::HLI => {
-                        self.registers.a =
-                            self.sub_with_carry(self.read_byte_at_hl());
+                        let value = self.read_byte_at_hl();
+                        self.registers.a = self.sub_with_carry(value);
                     }
                 }
                 if register == ArithmeticTarget::D8 {
@@ -682,10 +814,12 @@ impl CPU {
                         self.registers.a = self.and(self.registers.l);
                     }
                     ArithmeticTarget::D8 => {
-                        self.registers.a = self.and(self.read_next_byte());
+                        let value = self.read_next_byte();
+                        self.registers.a = self.and(value);
                     }
                     ArithmeticTarget::HLI => {
-                        let result = self.and(self.read_byte_at_hl());
+                        let value = self.read_byte_at_hl();
+                        let result = self.and(value);
                         self.registers.a = result;
                     }
                 }
@@ -721,10 +855,12 @@ impl CPU {
                         self.registers.a = self.or(self.registers.l);
                     }
                     ArithmeticTarget::D8 => {
-                        self.registers.a = self.or(self.read_next_byte());
+                        let value = self.read_next_byte();
+                        self.registers.a = self.or(value);
                     }
                     ArithmeticTarget::HLI => {
-                        let result = self.or(self.read_byte_at_hl());
+                        let value = self.read_byte_at_hl();
+                        let result = self.or(value);
                         self.registers.a = result;
                     }
                 }
@@ -760,10 +896,12 @@ impl CPU {
                         self.registers.a = self.xor(self.registers.l);
                     }
                     ArithmeticTarget::D8 => {
-                        self.registers.a = self.xor(self.read_next_byte());
+                        let value = self.read_next_byte();
+                        self.registers.a = self.xor(value);
                     }
                     ArithmeticTarget::HLI => {
-                        let result = self.xor(self.read_byte_at_hl());
+                        let value = self.read_byte_at_hl();
+                        let result = self.xor(value);
                         self.registers.a = result;
                     }
                 }
@@ -799,10 +937,12 @@ impl CPU {
                         self.compare(self.registers.l);
                     }
                     ArithmeticTarget::D8 => {
-                        self.compare(self.read_next_byte());
+                        let value = self.read_next_byte();
+                        self.compare(value);
                     }
                     ArithmeticTarget::HLI => {
-                        self.compare(self.read_byte_at_hl());
+                        let value = self.read_byte_at_hl();
+                        self.compare(value);
                     }
                 }
                 if register == ArithmeticTarget::D8 {
@@ -858,7 +998,8 @@ impl CPU {
                     (self.pc.wrapping_add(1), 8)
                 }
                 IncDecTarget::HLI => {
-                    let new_value = self.increment_8bit(self.read_byte_at_hl());
+                    let value = self.read_byte_at_hl();
+                    let new_value = self.increment_8bit(value);
                     self.write_byte_at_hl(new_value);
                     (self.pc.wrapping_add(1), 12)
                 }
@@ -912,7 +1053,8 @@ impl CPU {
                     (self.pc.wrapping_add(1), 8)
                 }
                 IncDecTarget::HLI => {
-                    let new_value = self.decrement_8bit(self.read_byte_at_hl());
+                    let value = self.read_byte_at_hl();
+                    let new_value = self.decrement_8bit(value);
                     self.write_byte_at_hl(new_value);
                     (self.pc.wrapping_add(1), 12)
                 }
@@ -969,10 +1111,13 @@ impl CPU {
                     PrefixTarget::E => self.bit_test(self.registers.e, bit_position),
                     PrefixTarget::H => self.bit_test(self.registers.h, bit_position),
                     PrefixTarget::L => self.bit_test(self.registers.l, bit_position),
-                    PrefixTarget::HLI => self.bit_test(self.read_byte_at_hl(), bit_position),
+                    PrefixTarget::HLI => {
+                        let value = self.read_byte_at_hl();
+                        self.bit_test(value, bit_position)
+                    }
                 }
                 match register {
-                    PrefixTarget::HLI => (self.pc.wrapping_add(2), 16),
+                    PrefixTarget::HLI => (self.pc.wrapping_add(2), 12),
                     _ => (self.pc.wrapping_add(2), 8),
                 }
             }
@@ -1000,7 +1145,8 @@ impl CPU {
                         self.registers.l = self.bit_reset(self.registers.l, bit_position)
                     }
                     PrefixTarget::HLI => {
-                        let result = self.bit_reset(self.read_byte_at_hl(), bit_position);
+                        let value = self.read_byte_at_hl();
+                        let result = self.bit_reset(value, bit_position);
                         self.write_byte_at_hl(result);
                     }
                 }
@@ -1033,7 +1179,8 @@ impl CPU {
                         self.registers.l = self.bit_set(self.registers.l, bit_position)
                     }
                     PrefixTarget::HLI => {
-                        let result = self.bit_set(self.read_byte_at_hl(), bit_position);
+                        let value = self.read_byte_at_hl();
+                        let result = self.bit_set(value, bit_position);
                         self.write_byte_at_hl(result);
                     }
                 }
@@ -1066,7 +1213,8 @@ impl CPU {
                         self.registers.l = self.shift_right_logical(self.registers.l)
                     }
                     PrefixTarget::HLI => {
-                        let result = self.shift_right_logical(self.read_byte_at_hl());
+                        let value = self.read_byte_at_hl();
+                        let result = self.shift_right_logical(value);
                         self.write_byte_at_hl(result);
                     }
                 }
@@ -1106,8 +1254,8 @@ impl CPU {
                             self.rotate_right_through_carry_set_zero(self.registers.l)
                     }
                     PrefixTarget::HLI => {
-                        let result =
-                            self.rotate_right_through_carry_set_zero(self.read_byte_at_hl());
+                        let value = self.read_byte_at_hl();
+                        let result = self.rotate_right_through_carry_set_zero(value);
                         self.write_byte_at_hl(result);
                     }
                 }
@@ -1147,8 +1295,8 @@ impl CPU {
                             self.rotate_left_through_carry_set_zero(self.registers.l)
                     }
                     PrefixTarget::HLI => {
-                        let result =
-                            self.rotate_left_through_carry_set_zero(self.read_byte_at_hl());
+                        let value = self.read_byte_at_hl();
+                        let result = self.rotate_left_through_carry_set_zero(value);
                         self.write_byte_at_hl(result);
                     }
                 }
@@ -1181,7 +1329,8 @@ impl CPU {
                         self.registers.l = self.rotate_right_set_zero(self.registers.l)
                     }
                     PrefixTarget::HLI => {
-                        let result = self.rotate_right_set_zero(self.read_byte_at_hl());
+                        let value = self.read_byte_at_hl();
+                        let result = self.rotate_right_set_zero(value);
                         self.write_byte_at_hl(result);
                     }
                 }
@@ -1214,7 +1363,8 @@ impl CPU {
                         self.registers.l = self.rotate_left_set_zero(self.registers.l)
                     }
                     PrefixTarget::HLI => {
-                        let result = self.rotate_left_set_zero(self.read_byte_at_hl());
+                        let value = self.read_byte_at_hl();
+                        let result = self.rotate_left_set_zero(value);
                         self.write_byte_at_hl(result);
                     }
                 }
@@ -1247,7 +1397,8 @@ impl CPU {
                         self.registers.l = self.shift_right_arithmetic(self.registers.l)
                     }
                     PrefixTarget::HLI => {
-                        let result = self.shift_right_arithmetic(self.read_byte_at_hl());
+                        let value = self.read_byte_at_hl();
+                        let result = self.shift_right_arithmetic(value);
                         self.write_byte_at_hl(result);
                     }
                 }
@@ -1280,7 +1431,8 @@ impl CPU {
                         self.registers.l = self.shift_left_arithmetic(self.registers.l)
                     }
                     PrefixTarget::HLI => {
-                        let result = self.shift_left_arithmetic(self.read_byte_at_hl());
+                        let value = self.read_byte_at_hl();
+                        let result = self.shift_left_arithmetic(value);
                         self.write_byte_at_hl(result);
                     }
                 }
@@ -1299,7 +1451,8 @@ impl CPU {
                     PrefixTarget::H => self.registers.h = self.swap_nibble(self.registers.h),
                     PrefixTarget::L => self.registers.l = self.swap_nibble(self.registers.l),
                     PrefixTarget::HLI => {
-                        let result = self.swap_nibble(self.read_byte_at_hl());
+                        let value = self.read_byte_at_hl();
+                        let result = self.swap_nibble(value);
                         self.write_byte_at_hl(result);
                     }
                 }
@@ -1619,7 +1772,7 @@ mod tests {
         //Special instructions
         #[test]
         fn nop() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.bus.write_byte(0, 0x00);
             cpu.step();
             assert_eq!(cpu.pc, 1);
@@ -1627,12 +1780,12 @@ mod tests {
 
         #[test]
         fn enable_interrupt() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.interrupt_enabled = false;
             cpu.bus.write_byte(0, 0xFB);
             cpu.bus.write_byte(1, 0x00);
             cpu.step();
-            assert_eq!(cpu.interrupt_enabled, true);
+            assert_eq!(cpu.interrupt_enabled, false);
             cpu.step();
             assert_eq!(cpu.interrupt_enabled, true);
             assert_eq!(cpu.pc, 2);
@@ -1640,7 +1793,7 @@ mod tests {
 
         #[test]
         fn disable_interrupt() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.bus.write_byte(0, 0xF3);
             cpu.bus.write_byte(1, 0x00);
             cpu.step();
@@ -1652,7 +1805,7 @@ mod tests {
 
         #[test]
         fn restart() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.pc = 100;
             cpu.sp = 0x10;
             cpu.bus.write_byte(100, 0xDF);
@@ -1665,7 +1818,7 @@ mod tests {
 
         #[test]
         fn return_enable_interrupt() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.pc = 100;
             cpu.sp = 0x10;
             cpu.bus.write_byte(100, 0xD9);
@@ -1679,7 +1832,7 @@ mod tests {
 
         #[test]
         fn decimal_adjust() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 0b0000_0101 + 0b0000_0101; // 5 + 5 inBCD
             cpu.execute(Instruction::DAA);
             assert_eq!(cpu.registers.a, 0b0001_0000);
@@ -1691,7 +1844,7 @@ mod tests {
         //LD on 16 bit registers
         #[test]
         fn load_word_into_16bit_register() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.bus.write_byte(0, 0x01); //LD BC d16
             cpu.bus.write_byte(1, 0x11);
             cpu.bus.write_byte(2, 0x01);
@@ -1701,7 +1854,7 @@ mod tests {
 
         #[test]
         fn load_16bit_value_to_address_at_bc_from_a() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 5;
             cpu.registers.set_bc(0x04);
             cpu.bus.write_byte(0, 0x02); //LD BC A
@@ -1711,7 +1864,7 @@ mod tests {
 
         #[test]
         fn load_16bit_value_to_address_at_hl_from_a() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 5;
             cpu.registers.set_hl(0x04);
             cpu.bus.write_byte(0, 0x22); // LD HL+ A
@@ -1722,7 +1875,7 @@ mod tests {
 
         #[test]
         fn load_16bit_value_to_a_from_address_from_a() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.set_bc(0x04);
             cpu.bus.write_byte(0, 0x0A); // LD A BC
             cpu.bus.write_byte(4, 0x0A);
@@ -1733,7 +1886,7 @@ mod tests {
         //LD 8 bit
         #[test]
         fn load_8bit_value_to_b() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.bus.write_byte(0, 0x06);
             cpu.bus.write_byte(1, 0x19);
             cpu.step();
@@ -1743,7 +1896,7 @@ mod tests {
 
         #[test]
         fn load_value_from_b_to_c() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.b = 15;
             cpu.bus.write_byte(0, 0x48);
             cpu.step();
@@ -1754,7 +1907,7 @@ mod tests {
 
         #[test]
         fn load_value_from_address_in_hl_to_e() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.bus.write_byte(0, 0x5E);
             cpu.bus.write_byte(3, 0x48);
             cpu.registers.set_hl(3);
@@ -1763,7 +1916,7 @@ mod tests {
         }
         #[test]
         fn load_value_to_address_in_hl_from_e() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.e = 5;
             cpu.bus.write_byte(0, 0x73);
             cpu.registers.set_hl(3);
@@ -1774,7 +1927,7 @@ mod tests {
         //Load byte address
         #[test]
         fn load_byte_address_from_a() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 101;
             cpu.bus.write_byte(0, 0xE0);
             cpu.bus.write_byte(1, 0x8D);
@@ -1784,7 +1937,7 @@ mod tests {
 
         #[test]
         fn load_a_from_byte_address() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.bus.write_byte(0, 0xF0);
             cpu.bus.write_byte(1, 0x8D);
             cpu.bus.write_byte(0xFF8D, 123);
@@ -1795,7 +1948,7 @@ mod tests {
         //Load last byte
         #[test]
         fn load_a_from_address_last_byte_in_c() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.bus.write_byte(0, 0xF2);
             cpu.bus.write_byte(0xFF85, 123);
             cpu.registers.c = 0x85;
@@ -1805,7 +1958,7 @@ mod tests {
 
         #[test]
         fn load_address_with_last_byte_in_c_from_a() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 101;
             cpu.bus.write_byte(0, 0xE2);
             cpu.registers.c = 0x85;
@@ -1815,18 +1968,18 @@ mod tests {
 
         #[test]
         fn load_hl_with_sp_and_byte() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.sp = 0x10;
             cpu.bus.write_byte(0, 0xF8);
             cpu.bus.write_byte(1, 0xE2);
             cpu.step();
-            assert_eq!(cpu.registers.get_hl(), 0xF2);
+            assert_eq!(cpu.registers.get_hl(), 0xFFF2);
         }
 
         // CALL
         #[test]
         fn call() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.sp = 0x10;
             cpu.bus.write_byte(0, 0xCC); //Jump if zero
             cpu.bus.write_byte(3, 0xC4); //jump if not zero
@@ -1842,7 +1995,7 @@ mod tests {
         //RET
         #[test]
         fn ret() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.sp = 0x10;
             cpu.bus.write_byte(0, 0xC4); //jump if not zero
             cpu.bus.write_byte(1, 0x14);
@@ -1862,7 +2015,7 @@ mod tests {
         //PUSH & POP
         #[test]
         fn push_and_pop() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.sp = 0x10;
             cpu.bus.write_byte(0, 0xC5);
             cpu.bus.write_byte(1, 0xD1);
@@ -1884,7 +2037,7 @@ mod tests {
         //JP
         #[test]
         fn jump() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.bus.write_byte(0, 0x00);
             cpu.bus.write_byte(1, 0xC3); //JP always
             cpu.bus.write_byte(2, 0x01);
@@ -1912,7 +2065,7 @@ mod tests {
 
         #[test]
         fn jump_hl() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.set_hl(412);
             cpu.bus.write_byte(0, 0xE9);
             cpu.step();
@@ -1921,7 +2074,7 @@ mod tests {
 
         #[test]
         fn jump_relative() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.bus.write_byte(0, 0x18); //JR always
             cpu.bus.write_byte(1, 0x09);
             cpu.step();
@@ -1935,7 +2088,7 @@ mod tests {
         // ADD tests
         #[test]
         fn add_instruction() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 2;
             cpu.registers.c = 4;
             cpu.execute(Instruction::ADD(ArithmeticTarget::C));
@@ -1944,7 +2097,7 @@ mod tests {
 
         #[test]
         fn add_byte_instruction() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 2;
             cpu.bus.write_byte(0, 0xC6);
             cpu.bus.write_byte(1, 0x01);
@@ -1954,7 +2107,7 @@ mod tests {
 
         #[test]
         fn add_caused_overflow() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 254;
             cpu.registers.c = 3;
             cpu.execute(Instruction::ADD(ArithmeticTarget::C));
@@ -1964,7 +2117,7 @@ mod tests {
 
         #[test]
         fn add_caused_half_carry() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 15;
             cpu.registers.c = 4;
             cpu.execute(Instruction::ADD(ArithmeticTarget::C));
@@ -1974,7 +2127,7 @@ mod tests {
 
         #[test]
         fn add_was_zero() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 0;
             cpu.registers.c = 0;
             cpu.execute(Instruction::ADD(ArithmeticTarget::C));
@@ -1990,7 +2143,7 @@ mod tests {
 
         #[test]
         fn add_sp() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.sp = 0x10;
             cpu.bus.write_byte(0, 0xE8);
             cpu.bus.write_byte(1, 0x10);
@@ -2001,7 +2154,7 @@ mod tests {
         //ADDHL
         #[test]
         fn addhl() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.set_hl(300);
             cpu.registers.set_bc(400);
             cpu.execute(Instruction::ADDHL(ArithmeticHLTarget::BC));
@@ -2010,7 +2163,7 @@ mod tests {
 
         #[test]
         fn addhl_caused_half_carry() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.set_hl(2023);
             cpu.registers.set_bc(101);
             cpu.execute(Instruction::ADDHL(ArithmeticHLTarget::BC));
@@ -2019,7 +2172,7 @@ mod tests {
         }
         #[test]
         fn addhl_caused_overflow() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.set_hl(65500);
             cpu.registers.set_bc(100);
             cpu.execute(Instruction::ADDHL(ArithmeticHLTarget::BC));
@@ -2031,7 +2184,7 @@ mod tests {
 
         #[test]
         fn addc() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 0b10;
             cpu.registers.b = 0b100;
             cpu.registers.f.carry = true;
@@ -2046,7 +2199,7 @@ mod tests {
         }
         #[test]
         fn addc_caused_half_carry() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 12;
             cpu.registers.c = 4;
             cpu.registers.f.carry = true;
@@ -2056,7 +2209,7 @@ mod tests {
         }
         #[test]
         fn addc_caused_overflow() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 251;
             cpu.registers.c = 4;
             cpu.registers.f.carry = true;
@@ -2068,7 +2221,7 @@ mod tests {
         //SUB
         #[test]
         fn sub() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 4;
             cpu.registers.c = 2;
             cpu.execute(Instruction::SUB(ArithmeticTarget::C));
@@ -2077,7 +2230,7 @@ mod tests {
         }
         #[test]
         fn sub_caused_half_carry() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 17;
             cpu.registers.c = 4;
             cpu.execute(Instruction::SUB(ArithmeticTarget::C));
@@ -2086,7 +2239,7 @@ mod tests {
         }
         #[test]
         fn sub_caused_overflow() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 2;
             cpu.registers.c = 4;
             cpu.execute(Instruction::SUB(ArithmeticTarget::C));
@@ -2098,7 +2251,7 @@ mod tests {
 
         #[test]
         fn sbc() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 4;
             cpu.registers.c = 2;
             cpu.registers.f.carry = true;
@@ -2109,7 +2262,7 @@ mod tests {
 
         #[test]
         fn sbc_caused_half_carry() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 20;
             cpu.registers.c = 4;
             cpu.registers.f.carry = true;
@@ -2120,7 +2273,7 @@ mod tests {
 
         #[test]
         fn sbc_caused_overflow() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 2;
             cpu.registers.c = 2;
             cpu.registers.f.carry = true;
@@ -2133,7 +2286,7 @@ mod tests {
 
         #[test]
         fn and() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 3;
             cpu.registers.c = 2;
             cpu.execute(Instruction::AND(ArithmeticTarget::C));
@@ -2144,7 +2297,7 @@ mod tests {
 
         #[test]
         fn or() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 3;
             cpu.registers.c = 4;
             cpu.execute(Instruction::OR(ArithmeticTarget::C));
@@ -2155,7 +2308,7 @@ mod tests {
 
         #[test]
         fn xor() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 7;
             cpu.registers.c = 4;
             cpu.execute(Instruction::XOR(ArithmeticTarget::C));
@@ -2165,7 +2318,7 @@ mod tests {
         //CP
         #[test]
         fn cp() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 7;
             cpu.registers.c = 8;
             cpu.execute(Instruction::CP(ArithmeticTarget::C));
@@ -2178,7 +2331,7 @@ mod tests {
         //INC
         #[test]
         fn increment_8bit_register() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.b = 7;
             cpu.execute(Instruction::INC(IncDecTarget::B));
             assert_eq!(cpu.registers.b, 8);
@@ -2187,7 +2340,7 @@ mod tests {
 
         #[test]
         fn increment_8bit_register_overflow() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.b = 255;
             cpu.execute(Instruction::INC(IncDecTarget::B));
             assert_eq!(cpu.registers.b, 0);
@@ -2197,7 +2350,7 @@ mod tests {
 
         #[test]
         fn increment_16bit_register() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.set_bc(1020);
             cpu.execute(Instruction::INC(IncDecTarget::BC));
             assert_eq!(cpu.registers.get_bc(), 1021);
@@ -2205,7 +2358,7 @@ mod tests {
 
         #[test]
         fn increment_16bit_register_overflow() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.set_bc(0xFFFF);
             cpu.execute(Instruction::INC(IncDecTarget::BC));
             assert_eq!(cpu.registers.get_bc(), 0);
@@ -2213,7 +2366,7 @@ mod tests {
 
         #[test]
         fn increment_16bit_register_byte_overflow() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.set_bc(0xFF);
             cpu.execute(Instruction::INC(IncDecTarget::BC));
             assert_eq!(cpu.registers.get_bc(), 0x0100);
@@ -2222,7 +2375,7 @@ mod tests {
         //DEC
         #[test]
         fn decrement_8bit_register() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.b = 7;
             cpu.execute(Instruction::DEC(IncDecTarget::B));
             assert_eq!(cpu.registers.b, 6);
@@ -2231,17 +2384,17 @@ mod tests {
 
         #[test]
         fn decrement_8bit_register_underflow() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.b = 0;
             cpu.execute(Instruction::DEC(IncDecTarget::B));
             assert_eq!(cpu.registers.b, 255);
             assert_eq!(cpu.registers.f.zero, false);
-            assert_eq!(cpu.registers.f.half_carry, false);
+            assert_eq!(cpu.registers.f.half_carry, true);
         }
 
         #[test]
         fn decrement_16bit_register() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.set_bc(1020);
             cpu.execute(Instruction::DEC(IncDecTarget::BC));
             assert_eq!(cpu.registers.get_bc(), 1019);
@@ -2249,7 +2402,7 @@ mod tests {
 
         #[test]
         fn decrement_16bit_register_underflow() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.set_bc(0x0);
             cpu.execute(Instruction::DEC(IncDecTarget::BC));
             assert_eq!(cpu.registers.get_bc(), 0xFFFF);
@@ -2259,7 +2412,7 @@ mod tests {
 
         #[test]
         fn decrement_16bit_register_byte_underflow() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.set_bc(0x100);
             cpu.execute(Instruction::DEC(IncDecTarget::BC));
             assert_eq!(cpu.registers.get_bc(), 0xFF);
@@ -2267,7 +2420,7 @@ mod tests {
 
         #[test]
         fn ccf() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.f.carry = true;
             cpu.execute(Instruction::CCF);
             assert_eq!(cpu.registers.f.carry, false);
@@ -2275,7 +2428,7 @@ mod tests {
 
         #[test]
         fn scf() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.f.carry = true;
             cpu.execute(Instruction::SCF);
             assert_eq!(cpu.registers.f.carry, true);
@@ -2285,7 +2438,7 @@ mod tests {
 
         #[test]
         fn rra() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 0b00000101;
             cpu.registers.f.carry = true;
             cpu.execute(Instruction::RRA);
@@ -2295,7 +2448,7 @@ mod tests {
 
         #[test]
         fn rla() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 0b00000101;
             cpu.registers.f.carry = true;
             cpu.execute(Instruction::RLA);
@@ -2305,7 +2458,7 @@ mod tests {
 
         #[test]
         fn rrca() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 0b00000101;
             cpu.registers.f.carry = true;
             cpu.execute(Instruction::RRCA);
@@ -2315,7 +2468,7 @@ mod tests {
 
         #[test]
         fn rlca() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 0b00000101;
             cpu.registers.f.carry = true;
             cpu.execute(Instruction::RLCA);
@@ -2325,7 +2478,7 @@ mod tests {
 
         #[test]
         fn cpl() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.a = 0b01100101;
             cpu.execute(Instruction::CPL);
             assert_eq!(cpu.registers.a, 0b10011010);
@@ -2335,7 +2488,7 @@ mod tests {
 
         #[test]
         fn bit() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.b = 0b10011000;
             cpu.registers.f.zero = true; //result of bit test will be stored here
             cpu.execute(Instruction::BIT(PrefixTarget::B, BitPosition::B4));
@@ -2346,7 +2499,7 @@ mod tests {
 
         #[test]
         fn reset() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.b = 0b10011000;
             cpu.execute(Instruction::RES(PrefixTarget::B, BitPosition::B4));
             cpu.execute(Instruction::BIT(PrefixTarget::B, BitPosition::B4));
@@ -2356,7 +2509,7 @@ mod tests {
         use super::*;
         #[test]
         fn set() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.b = 0b10010000;
             cpu.execute(Instruction::BIT(PrefixTarget::B, BitPosition::B3));
             assert_eq!(cpu.registers.f.zero, true);
@@ -2367,7 +2520,7 @@ mod tests {
 
         #[test]
         fn srl() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.b = 0b10010000;
             cpu.execute(Instruction::SRL(PrefixTarget::B));
             assert_eq!(cpu.registers.f.zero, false);
@@ -2382,7 +2535,7 @@ mod tests {
 
         #[test]
         fn rr() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.b = 0b10010000;
             cpu.execute(Instruction::RR(PrefixTarget::B));
             assert_eq!(cpu.registers.f.zero, false);
@@ -2398,7 +2551,7 @@ mod tests {
 
         #[test]
         fn rl() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.b = 0b10010000;
             cpu.execute(Instruction::RL(PrefixTarget::B));
             assert_eq!(cpu.registers.f.zero, false);
@@ -2414,7 +2567,7 @@ mod tests {
 
         #[test]
         fn rrc() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.b = 0b10010000;
             cpu.execute(Instruction::RRC(PrefixTarget::B));
             assert_eq!(cpu.registers.f.zero, false);
@@ -2430,7 +2583,7 @@ mod tests {
 
         #[test]
         fn rlc() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.b = 0b10010000;
             cpu.execute(Instruction::RLC(PrefixTarget::B));
             assert_eq!(cpu.registers.f.zero, false);
@@ -2446,7 +2599,7 @@ mod tests {
 
         #[test]
         fn sra() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.b = 0b10010000;
             cpu.execute(Instruction::SRA(PrefixTarget::B));
             assert_eq!(cpu.registers.f.zero, false);
@@ -2461,7 +2614,7 @@ mod tests {
         }
 
         fn sla() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.b = 0b10010000;
             cpu.execute(Instruction::SLA(PrefixTarget::B));
             assert_eq!(cpu.registers.f.zero, false);
@@ -2477,7 +2630,7 @@ mod tests {
 
         #[test]
         fn swap() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.registers.b = 0b10010110;
             cpu.execute(Instruction::SWAP(PrefixTarget::B));
             assert_eq!(cpu.registers.f.zero, false);
@@ -2496,7 +2649,7 @@ mod tests {
         use super::*;
         #[test]
         fn pc_increase_with_step() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.bus.write_byte(0, 0x00);
             cpu.bus.write_byte(1, 0x3C);
             cpu.bus.write_byte(2, 0x13);
@@ -2515,7 +2668,7 @@ mod tests {
         use super::*;
         #[test]
         fn run_prefixed_command() {
-            let mut cpu = CPU::new(None, vec![0; 0x10000]);
+            let mut cpu = CPU::new(None, Cartridge::empty());
             cpu.bus.write_byte(0, 0xCB);
             cpu.bus.write_byte(1, 0x37);
             cpu.registers.a = 0xEF;
@@ -2524,4 +2677,417 @@ mod tests {
             assert_eq!(cpu.registers.a, 0xFE);
         }
     }
+
+    mod save_state {
+        use super::*;
+        use crate::gpu::TilePixelValue;
+        #[test]
+        fn restores_machine() {
+            let mut cpu = CPU::new(None, Cartridge::empty());
+            cpu.bus.write_byte(0, 0x3C);
+            cpu.bus.write_byte(1, 0x3C);
+            cpu.bus.write_byte(0xC000, 0x42);
+            cpu.bus.write_byte(0x8000, 0xFF);
+            cpu.bus.write_byte(0x8001, 0x00);
+            cpu.bus.write_byte(0xFF47, 0x1B);
+            cpu.step();
+            let state = cpu.save_state();
+
+            cpu.step();
+            cpu.bus.write_byte(0xC000, 0x00);
+            cpu.bus.write_byte(0x8000, 0x00);
+            cpu.bus.write_byte(0xFF47, 0xE4);
+            assert_eq!(cpu.load_state(&state), Ok(()));
+
+            assert_eq!(cpu.pc, 1);
+            assert_eq!(cpu.registers.a, 1);
+            assert_eq!(cpu.bus.read_byte(0xC000), 0x42);
+            assert_eq!(u8::from(cpu.bus.gpu.background_window_palette), 0x1B);
+            assert!(cpu.bus.gpu.tile_set[0][0][0] == TilePixelValue::One);
+        }
+
+        #[test]
+        fn rejects_other_cartridge_type() {
+            let cpu = CPU::new(None, Cartridge::empty());
+            let state = cpu.save_state();
+
+            let mut data = vec![0; 0x8000];
+            data[0x147] = 0x01;
+            let mut other = CPU::new(None, Cartridge::new(data).unwrap());
+            assert_eq!(other.load_state(&state), Err(StateError::CartridgeMismatch));
+        }
+
+        #[test]
+        fn truncated_state_changes_nothing() {
+            let mut cpu = CPU::new(None, Cartridge::empty());
+            cpu.bus.write_byte(0xC000, 0x42);
+            let mut state = cpu.save_state();
+            //Cut inside the bus, after the CPU registers, with a header that still matches
+            state.truncate(10 + 0x200);
+            state[6..10].copy_from_slice(&0x200u32.to_le_bytes());
+
+            let mut cpu = CPU::new(None, Cartridge::empty());
+            cpu.pc = 0x1234;
+            cpu.sp = 0xD000;
+            cpu.registers.set_bc(0xBEEF);
+            cpu.bus.write_byte(0xC000, 0x24);
+            cpu.bus.write_byte(0xFF47, 0x1B);
+            let before = cpu.save_state();
+
+            assert_eq!(cpu.load_state(&state), Err(StateError::UnexpectedEnd));
+            assert_eq!(cpu.pc, 0x1234);
+            assert_eq!(cpu.sp, 0xD000);
+            assert_eq!(cpu.registers.get_bc(), 0xBEEF);
+            assert_eq!(cpu.bus.read_byte(0xC000), 0x24);
+            assert_eq!(u8::from(cpu.bus.gpu.background_window_palette), 0x1B);
+            assert!(cpu.save_state() == before);
+        }
+    }
+
+    mod halt {
+        use super::*;
+
+        fn halted_cpu(program: &[u8]) -> CPU {
+            let mut cpu = CPU::new(None, Cartridge::empty());
+            for (address, byte) in program.iter().enumerate() {
+                cpu.bus.write_byte(address as u16, *byte);
+            }
+            cpu.sp = 0xFFFE;
+            cpu
+        }
+
+        #[test]
+        fn waits_for_interrupt() {
+            let mut cpu = halted_cpu(&[0x76, 0x00]);
+            cpu.bus.interrupts_enabled.timer = true;
+            cpu.step();
+            assert_eq!(cpu.pc, 1);
+            assert_eq!(cpu.step(), 4);
+            assert_eq!(cpu.step(), 4);
+            assert_eq!(cpu.pc, 1);
+
+            cpu.bus.interrupt_flags.timer = true;
+            cpu.step();
+            assert_eq!(cpu.pc, 0x50);
+            assert_eq!(cpu.pop(), 1);
+        }
+
+        #[test]
+        fn wakes_with_interrupts_disabled() {
+            let mut cpu = halted_cpu(&[0x76, 0x3C]);
+            cpu.interrupt_enabled = false;
+            cpu.bus.interrupts_enabled.timer = true;
+            cpu.step();
+            cpu.step();
+            assert_eq!(cpu.pc, 1);
+
+            cpu.bus.interrupt_flags.timer = true;
+            cpu.step();
+            cpu.step();
+            assert_eq!(cpu.pc, 2);
+            assert_eq!(cpu.registers.a, 1);
+            assert!(cpu.bus.interrupt_flags.timer);
+        }
+
+        #[test]
+        fn halt_bug_reads_next_byte_twice() {
+            let mut cpu = halted_cpu(&[0x76, 0x3C, 0x00]);
+            cpu.interrupt_enabled = false;
+            cpu.bus.interrupts_enabled.timer = true;
+            cpu.bus.interrupt_flags.timer = true;
+            cpu.step();
+            cpu.step();
+            assert_eq!(cpu.pc, 1);
+            cpu.step();
+            assert_eq!(cpu.pc, 2);
+            assert_eq!(cpu.registers.a, 2);
+
+            let mut cpu = halted_cpu(&[0x76, 0x3E, 0x12]);
+            cpu.interrupt_enabled = false;
+            cpu.bus.interrupts_enabled.timer = true;
+            cpu.bus.interrupt_flags.timer = true;
+            cpu.step();
+            cpu.step();
+            assert_eq!(cpu.registers.a, 0x3E);
+            assert_eq!(cpu.pc, 2);
+        }
+    }
+
+    mod stop {
+        use super::*;
+
+        #[test]
+        fn stops_until_joypad_pressed() {
+            let mut cpu = CPU::new(None, Cartridge::empty());
+            cpu.bus.write_byte(0, 0x10);
+            cpu.bus.write_byte(2, 0x3C);
+            cpu.bus.write_byte(0xFF00, 0x20);
+            cpu.bus.step(0x3000);
+            cpu.step();
+            assert_eq!(cpu.pc, 2);
+            assert_eq!(cpu.bus.read_byte(0xFF04), 0);
+
+            for _ in 0..1000 {
+                cpu.step();
+            }
+            assert_eq!(cpu.pc, 2);
+            assert_eq!(cpu.bus.read_byte(0xFF04), 0);
+
+            cpu.bus.joypad.up();
+            cpu.step();
+            cpu.step();
+            assert_eq!(cpu.pc, 3);
+            assert_eq!(cpu.registers.a, 1);
+        }
+
+        #[test]
+        fn button_held_acts_like_halt() {
+            let mut cpu = CPU::new(None, Cartridge::empty());
+            cpu.bus.write_byte(0, 0x10);
+            cpu.bus.write_byte(0xFF00, 0x20);
+            cpu.bus.joypad.up();
+            cpu.bus.step(0x3000);
+            cpu.step();
+            assert_eq!(cpu.pc, 2);
+            assert!(cpu.is_halted);
+            assert_eq!(cpu.bus.read_byte(0xFF04), 0x30);
+        }
+    }
+
+    mod interrupts {
+        use super::*;
+
+        fn interrupted_cpu(pc: u16, sp: u16) -> CPU {
+            let mut cpu = CPU::new(None, Cartridge::empty());
+            cpu.pc = pc;
+            cpu.sp = sp;
+            cpu.bus.interrupts_enabled.from_byte(0x1F);
+            cpu.bus.interrupt_flags.timer = true;
+            cpu.bus.interrupt_flags.vertical_blank = true;
+            cpu
+        }
+
+        #[test]
+        fn services_highest_priority_only() {
+            let mut cpu = interrupted_cpu(0, 0xD000);
+            assert_eq!(cpu.step(), 4 + 20);
+            assert_eq!(cpu.pc, 0x40);
+            assert_eq!(cpu.sp, 0xCFFE);
+            assert_eq!(cpu.pop(), 1);
+            assert!(!cpu.bus.interrupt_flags.vertical_blank);
+            assert!(cpu.bus.interrupt_flags.timer);
+        }
+
+        #[test]
+        fn push_to_ie_cancels_dispatch() {
+            //The high byte of PC 0x0001 clears IE
+            let mut cpu = interrupted_cpu(0, 0x0000);
+            cpu.step();
+            assert_eq!(cpu.pc, 0x0000);
+            assert_eq!(cpu.sp, 0xFFFE);
+            assert!(cpu.bus.interrupt_flags.vertical_blank);
+            assert!(cpu.bus.interrupt_flags.timer);
+
+            //The high byte of PC 0x0401 leaves only the timer enabled
+            let mut cpu = interrupted_cpu(0x0400, 0x0000);
+            cpu.step();
+            assert_eq!(cpu.pc, 0x50);
+            assert!(cpu.bus.interrupt_flags.vertical_blank);
+        }
+    }
+
+    mod ime {
+        use super::*;
+
+        fn pending_cpu(program: &[u8]) -> CPU {
+            let mut cpu = CPU::new(None, Cartridge::empty());
+            cpu.sp = 0xD000;
+            cpu.interrupt_enabled = false;
+            for (address, byte) in program.iter().enumerate() {
+                cpu.bus.write_byte(address as u16, *byte);
+            }
+            cpu.bus.interrupts_enabled.from_byte(0x01);
+            cpu.bus.interrupt_flags.vertical_blank = true;
+            cpu
+        }
+
+        #[test]
+        fn ei_waits_for_next_instruction() {
+            let mut cpu = pending_cpu(&[0xFB, 0x00, 0x00]);
+            assert_eq!(cpu.step(), 4);
+            assert_eq!(cpu.pc, 1);
+            assert!(!cpu.interrupt_enabled);
+
+            assert_eq!(cpu.step(), 4 + 20);
+            assert_eq!(cpu.pc, 0x40);
+            assert_eq!(cpu.pop(), 2);
+            assert!(!cpu.interrupt_enabled);
+        }
+
+        #[test]
+        fn ei_di_never_enables() {
+            let mut cpu = pending_cpu(&[0xFB, 0xF3, 0x00]);
+            cpu.step();
+            cpu.step();
+            cpu.step();
+            assert_eq!(cpu.pc, 3);
+            assert!(!cpu.interrupt_enabled);
+        }
+
+        #[test]
+        fn ei_ei_enables_after_second() {
+            let mut cpu = pending_cpu(&[0xFB, 0xFB, 0x00]);
+            cpu.step();
+            assert_eq!(cpu.step(), 4 + 20);
+            assert_eq!(cpu.pc, 0x40);
+            assert_eq!(cpu.pop(), 2);
+        }
+
+        #[test]
+        fn ei_halt_returns_to_halt() {
+            let mut cpu = pending_cpu(&[0xFB, 0x76, 0x00]);
+            cpu.step();
+            cpu.step();
+            assert_eq!(cpu.pc, 0x40);
+            assert!(!cpu.is_halted);
+            assert_eq!(cpu.pop(), 1);
+        }
+
+        #[test]
+        fn reti_enables_immediately() {
+            let mut cpu = pending_cpu(&[]);
+            cpu.pc = 0x100;
+            cpu.bus.write_byte(0x100, 0xD9);
+            cpu.push(0x0200);
+            assert_eq!(cpu.step(), 16 + 20);
+            assert_eq!(cpu.pc, 0x40);
+            assert_eq!(cpu.pop(), 0x0200);
+        }
+    }
+
+    mod timing {
+        use super::*;
+
+        //LDH A,(DIV) reads DIV in its third M-cycle
+        fn read_divider_after(counter: u16) -> u8 {
+            let mut cpu = CPU::new(None, Cartridge::empty());
+            cpu.bus.write_byte(0, 0xF0);
+            cpu.bus.write_byte(1, 0x04);
+            cpu.bus.step(counter);
+            assert_eq!(cpu.step(), 12);
+            cpu.registers.a
+        }
+
+        #[test]
+        fn read_happens_in_its_m_cycle() {
+            assert_eq!(read_divider_after(256 - 13), 0);
+            assert_eq!(read_divider_after(256 - 12), 1);
+        }
+
+        #[test]
+        fn conditional_return_checks_before_popping() {
+            //RET NZ pops DIV as the low byte of PC in its third M-cycle
+            let mut cpu = CPU::new(None, Cartridge::empty());
+            cpu.bus.write_byte(0, 0xC0);
+            cpu.sp = 0xFF04;
+            cpu.registers.f.zero = false;
+            cpu.bus.step(256 - 12);
+            assert_eq!(cpu.step(), 20);
+            assert_eq!(cpu.pc & 0xFF, 1);
+        }
+
+        //Runs one instruction from WRAM with HL pointing at WRAM, returns its cycles and DIV after
+        fn run_from(program: &[u8], counter: u16) -> (u16, u8) {
+            let mut cpu = CPU::new(None, Cartridge::empty());
+            cpu.pc = 0xC000;
+            cpu.registers.set_hl(0xC100);
+            for (offset, byte) in program.iter().enumerate() {
+                cpu.bus.write_byte(0xC000 + offset as u16, *byte);
+            }
+            cpu.bus.step(counter);
+            let cycles = cpu.step();
+            (cycles, cpu.bus.read_byte(0xFF04))
+        }
+
+        #[test]
+        fn store_to_hl_takes_an_extra_m_cycle() {
+            assert_eq!(run_from(&[0x36, 0x42], 256 - 13), (12, 0));
+            assert_eq!(run_from(&[0x36, 0x42], 256 - 12), (12, 1));
+            assert_eq!(run_from(&[0x70], 256 - 9), (8, 0));
+            assert_eq!(run_from(&[0x70], 256 - 8), (8, 1));
+        }
+
+        #[test]
+        fn bit_test_of_hl_only_reads() {
+            for opcode in (0x46..0x80).step_by(8) {
+                assert_eq!(run_from(&[0xCB, opcode], 256 - 13), (12, 0));
+                assert_eq!(run_from(&[0xCB, opcode], 256 - 12), (12, 1));
+            }
+        }
+
+        //DMG T-cycles with all flags clear, so NZ and NC branches are taken, 0 is undefined
+        const OPCODE_CYCLES: [u16; 256] = [
+             4, 12,  8,  8,  4,  4,  8,  4, 20,  8,  8,  8,  4,  4,  8,  4,
+             4, 12,  8,  8,  4,  4,  8,  4, 12,  8,  8,  8,  4,  4,  8,  4,
+            12, 12,  8,  8,  4,  4,  8,  4,  8,  8,  8,  8,  4,  4,  8,  4,
+            12, 12,  8,  8, 12, 12, 12,  4,  8,  8,  8,  8,  4,  4,  8,  4,
+             4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4,
+             4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4,
+             4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4,
+             8,  8,  8,  8,  8,  8,  4,  8,  4,  4,  4,  4,  4,  4,  8,  4,
+             4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4,
+             4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4,
+             4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4,
+             4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4,
+            20, 12, 16, 16, 24, 16,  8, 16,  8, 16, 12,  0, 12, 24,  8, 16,
+            20, 12, 16,  0, 24, 16,  8, 16,  8, 16, 12,  0, 12,  0,  8, 16,
+            12, 12,  8,  0,  0, 16,  8, 16, 16,  4, 16,  0,  0,  0,  8, 16,
+            12, 12,  8,  4,  0, 16,  8, 16, 12,  8, 16,  4,  0,  0,  8, 16,
+        ];
+
+        #[test]
+        fn every_opcode_ticks_its_cycles() {
+            for opcode in 0..=0xFF {
+                let expected = OPCODE_CYCLES[opcode as usize];
+                if expected == 0 {
+                    continue;
+                }
+                let mut cpu = CPU::new(None, Cartridge::empty());
+                cpu.pc = 0xC000;
+                cpu.sp = 0xD000;
+                cpu.registers.set_hl(0xC100);
+                cpu.bus.write_byte(0xC000, opcode);
+                cpu.bus.write_byte(0xC001, 0x00);
+                cpu.bus.write_byte(0xC002, 0x00);
+                assert_eq!(cpu.step(), expected, "opcode 0x{:02X}", opcode);
+            }
+            for opcode in 0..=0xFF {
+                let expected = match opcode & 0x07 {
+                    6 if (0x40..0x80).contains(&opcode) => 12,
+                    6 => 16,
+                    _ => 8,
+                };
+                assert_eq!(run_from(&[0xCB, opcode], 0).0, expected, "opcode 0xCB 0x{:02X}", opcode);
+            }
+        }
+    }
+
+    mod lockup {
+        use super::*;
+
+        #[test]
+        fn undefined_opcode_locks_up() {
+            let mut cpu = CPU::new(None, Cartridge::empty());
+            cpu.pc = 0x0100;
+            cpu.bus.write_byte(0x0100, 0xD3);
+            assert_eq!(cpu.try_step(), Err(EmulatorError::UndefinedOpcode(0xD3, 0x0100)));
+
+            //Interrupts don't get it going again
+            cpu.bus.interrupts_enabled.from_byte(0x01);
+            cpu.bus.interrupt_flags.vertical_blank = true;
+            assert_eq!(cpu.try_step(), Ok(4));
+            assert_eq!(cpu.step(), 4);
+            assert_eq!(cpu.pc, 0x0100);
+        }
+    }
 }
@@ -0,0 +1,163 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
+/* OAM DMA - writing 0xFF46 copies 160 bytes from XX00-XX9F to OAM
+ * One byte is copied every M-cycle after a one M-cycle startup, the CPU can only
+ * use HRAM and the IO registers while the transfer runs.
+ * Writing 0xFF46 again during a transfer restarts it, the old transfer keeps going
+ * (and keeps the bus blocked) until the new one has started.
+ */
+pub const DMA_LENGTH: u16 = 160;
+const START_DELAY: u8 = 1;
+const M_CYCLE: u16 = 4;
+
+pub struct OamDma {
+    pub register: u8, //last value written to 0xFF46
+    source: u16,
+    offset: u16, //next byte copied
+    active: bool,
+    pending: Option<(u16, u8)>, //source and M-cycles left before a new transfer starts
+    cycles: u16,
+    pub value: u8, //last byte copied, what the CPU sees when it reads the same bus
+}
+
+impl OamDma {
+    pub fn new() -> OamDma {
+        OamDma {
+            register: 0xFF,
+            source: 0,
+            offset: 0,
+            active: false,
+            pending: None,
+            cycles: 0,
+            value: 0xFF,
+        }
+    }
+
+    pub fn start(&mut self, byte: u8) {
+        self.register = byte;
+        //The DMA unit sees 0xE000-0xFFFF as echo RAM
+        let high = if byte >= 0xE0 { byte - 0x20 } else { byte };
+        self.pending = Some(((high as u16) << 8, START_DELAY));
+    }
+
+    /* True while the CPU is locked out of everything but HRAM */
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    pub fn source(&self) -> u16 {
+        self.source
+    }
+
+    /* Advances by T-cycles, returns the source addresses and OAM offsets copied */
+    pub fn step(&mut self, cycles: u16) -> Vec<(u16, usize)> {
+        let mut copies = Vec::new();
+        if !self.active && self.pending.is_none() {
+            return copies;
+        }
+
+        self.cycles += cycles;
+        while self.cycles >= M_CYCLE {
+            self.cycles -= M_CYCLE;
+            if let Some(copy) = self.tick() {
+                copies.push(copy);
+            }
+        }
+        if !self.active && self.pending.is_none() {
+            self.cycles = 0;
+        }
+        copies
+    }
+
+    fn tick(&mut self) -> Option<(u16, usize)> {
+        let mut copy = None;
+        if self.active {
+            copy = Some((self.source + self.offset, self.offset as usize));
+            self.offset += 1;
+            if self.offset == DMA_LENGTH {
+                self.active = false;
+            }
+        }
+
+        if let Some((source, delay)) = self.pending {
+            if delay <= 1 {
+                self.pending = None;
+                self.source = source;
+                self.offset = 0;
+                self.active = true;
+            } else {
+                self.pending = Some((source, delay - 1));
+            }
+        }
+        copy
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.register);
+        state.write_u16(self.source);
+        state.write_u16(self.offset);
+        state.write_bool(self.active);
+        state.write_bool(self.pending.is_some());
+        let (pending_source, pending_delay) = self.pending.unwrap_or((0, 0));
+        state.write_u16(pending_source);
+        state.write_u8(pending_delay);
+        state.write_u16(self.cycles);
+        state.write_u8(self.value);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.register = state.read_u8()?;
+        self.source = state.read_u16()?;
+        self.offset = state.read_u16()?;
+        if self.offset > DMA_LENGTH {
+            return Err(StateError::InvalidValue("DMA offset", self.offset as u8));
+        }
+        self.active = state.read_bool()?;
+        let pending = state.read_bool()?;
+        let pending_source = state.read_u16()?;
+        let pending_delay = state.read_u8()?;
+        self.pending = if pending { Some((pending_source, pending_delay)) } else { None };
+        self.cycles = state.read_u16()?;
+        self.value = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copies_one_byte_per_m_cycle_after_startup() {
+        let mut dma = OamDma::new();
+        dma.start(0xC1);
+        assert!(dma.step(4).is_empty());
+        assert!(dma.active());
+
+        let copies = dma.step(8);
+        assert_eq!(copies, vec![(0xC100, 0), (0xC101, 1)]);
+        let copies = dma.step(158 * 4);
+        assert_eq!(copies.len(), 158);
+        assert_eq!(copies[157], (0xC19F, 159));
+        assert!(!dma.active());
+    }
+
+    #[test]
+    fn restart_keeps_old_transfer_until_new_one_starts() {
+        let mut dma = OamDma::new();
+        dma.start(0xC0);
+        dma.step(4 * 11);
+        dma.start(0xD0);
+        assert_eq!(dma.step(4), vec![(0xC00A, 10)]);
+        assert_eq!(dma.step(4), vec![(0xD000, 0)]);
+    }
+
+    #[test]
+    fn echo_ram_source() {
+        let mut dma = OamDma::new();
+        dma.start(0xFE);
+        dma.step(8);
+        assert_eq!(dma.source(), 0xDE00);
+        assert_eq!(dma.register, 0xFE);
+    }
+}
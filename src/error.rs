@@ -0,0 +1,20 @@
+use std::fmt;
+
+/* Errors a running machine can hit because of what the ROM does,
+ * they never stop the emulator itself
+ */
+#[derive(Debug, PartialEq)]
+pub enum EmulatorError {
+    UndefinedOpcode(u8, u16), //opcode and its address, the CPU locks up
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmulatorError::UndefinedOpcode(opcode, address) => write!(
+                f, "Undefined opcode 0x{:02X} at 0x{:04X}, CPU locked up", opcode, address),
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {}
@@ -0,0 +1,379 @@
+use std::collections::VecDeque;
+
+use super::{tile_map_offset, Color, ObjectPalette, TilePixelValue, TileRow, GPU, SCREEN_WIDTH};
+use crate::state::{StateError, StateReader, StateWriter};
+
+/* Pixel FIFO renderer, runs one dot at a time during mode 3
+ *
+ * The background fetcher takes 2 dots for each step:
+ * read the tile number, read the low byte, read the high byte
+ * and then waits until the background FIFO is empty to push the 8 pixels.
+ * Every dot a pixel is shifted out of the background FIFO, mixed with the
+ * object FIFO and drawn, so palette and scroll changes apply mid-line.
+ *
+ * Mode 3 is 172 dots plus:
+ * SCX % 8 pixels discarded at the start of the line
+ * about 6 dots when the fetcher restarts for the window
+ * 6-11 dots for each object, the fetcher has to finish its tile first
+ */
+const FETCHER_STEP_CYCLES: u8 = 2;
+const LINE_START_CYCLES: u8 = 6;
+const OBJECT_FETCH_CYCLES: u8 = 6;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum FetchStep {
+    TileNumber,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+#[derive(Copy, Clone)]
+struct ObjectPixel {
+    value: TilePixelValue,
+    palette: ObjectPalette,
+    behind_background: bool,
+}
+
+pub struct PixelFifo {
+    background: VecDeque<TilePixelValue>,
+    objects: VecDeque<ObjectPixel>,
+
+    step: FetchStep,
+    step_cycles: u8,
+    fetch_x: u8, //tile column, counted from the start of the line or the window
+    tile_number: u8,
+    tile_row: TileRow,
+    fetching_window: bool,
+
+    start_cycles: u8,
+    object_cycles: u8,
+    discard: u8,
+    x: u8, //next pixel drawn on the line
+
+    line_objects: Vec<usize>,
+    next_object: usize,
+}
+
+impl Default for PixelFifo {
+    fn default() -> Self {
+        PixelFifo {
+            background: VecDeque::with_capacity(8),
+            objects: VecDeque::with_capacity(8),
+
+            step: FetchStep::TileNumber,
+            step_cycles: 0,
+            fetch_x: 0,
+            tile_number: 0,
+            tile_row: Default::default(),
+            fetching_window: false,
+
+            start_cycles: 0,
+            object_cycles: 0,
+            discard: 0,
+            x: 0,
+
+            line_objects: Vec::new(),
+            next_object: 0,
+        }
+    }
+}
+
+impl PixelFifo {
+    /* Start of mode 3, the objects come from the OAM scan of mode 2 */
+    pub fn start_line(&mut self, gpu: &mut GPU) {
+        self.background.clear();
+        self.objects.clear();
+        self.step = FetchStep::TileNumber;
+        self.step_cycles = 0;
+        self.fetch_x = 0;
+        self.fetching_window = false;
+
+        //The first tile is fetched twice and thrown away
+        self.start_cycles = LINE_START_CYCLES;
+        self.object_cycles = 0;
+        self.discard = gpu.scroll_x % 8;
+        self.x = 0;
+
+        self.line_objects = gpu.line_objects();
+        self.next_object = 0;
+
+        if gpu.lcd_y_coordinate == gpu.window_y {
+            gpu.window_y_triggered = true;
+        }
+    }
+
+    /* Runs a single dot of mode 3, returns true when the line is done */
+    pub fn tick(&mut self, gpu: &mut GPU) -> bool {
+        if self.start_cycles > 0 {
+            self.start_cycles -= 1;
+            return false;
+        }
+
+        self.check_window(gpu);
+
+        if self.object_cycles > 0 {
+            self.object_cycles -= 1;
+            if self.object_cycles == 0 {
+                self.merge_object(gpu);
+            }
+            return false;
+        }
+
+        //Output stops while the background fetch in progress finishes, then the object is fetched
+        if self.object_due(gpu) {
+            if self.step != FetchStep::Push || self.background.is_empty() {
+                self.step_fetcher(gpu);
+            }
+            if self.step == FetchStep::Push && !self.background.is_empty() {
+                self.object_cycles = OBJECT_FETCH_CYCLES - 1;
+            }
+            return false;
+        }
+
+        self.step_fetcher(gpu);
+
+        if let Some(value) = self.background.pop_front() {
+            if self.discard > 0 {
+                self.discard -= 1;
+                return false;
+            }
+            let object = self.objects.pop_front();
+            self.draw_pixel(gpu, value, object);
+
+            self.x += 1;
+            if self.x as usize == SCREEN_WIDTH {
+                if self.fetching_window {
+                    gpu.window_line += 1;
+                }
+                return true;
+            }
+        }
+        false
+    }
+
+    fn check_window(&mut self, gpu: &GPU) {
+        if self.fetching_window ||
+            !gpu.window_display_enabled ||
+            !gpu.background_display_enabled ||
+            !gpu.window_y_triggered ||
+            gpu.window_x > 166 ||
+            (self.x as u16 + 7) < gpu.window_x as u16 {
+            return;
+        }
+
+        //The fetcher starts over on the window, WX below 7 cuts off its left edge
+        self.fetching_window = true;
+        self.background.clear();
+        self.step = FetchStep::TileNumber;
+        self.step_cycles = 0;
+        self.fetch_x = 0;
+        self.discard = 7u8.saturating_sub(gpu.window_x);
+    }
+
+    fn object_due(&self, gpu: &GPU) -> bool {
+        if !gpu.obj_display_enable {
+            return false;
+        }
+        match self.line_objects.get(self.next_object) {
+            Some(index) => gpu.obj_data[*index].x <= self.x as i16,
+            None => false,
+        }
+    }
+
+    fn step_fetcher(&mut self, gpu: &GPU) {
+        if self.step == FetchStep::Push {
+            if !self.background.is_empty() {
+                return;
+            }
+            //The next fetch starts in the same dot as the push
+            self.background.extend(self.tile_row.iter());
+            self.fetch_x += 1;
+            self.step = FetchStep::TileNumber;
+        }
+
+        self.step_cycles += 1;
+        if self.step_cycles < FETCHER_STEP_CYCLES {
+            return;
+        }
+        self.step_cycles = 0;
+
+        self.step = match self.step {
+            FetchStep::TileNumber => {
+                let map_offset = if self.fetching_window {
+                    tile_map_offset(&gpu.window_tile_map) +
+                        (gpu.window_line as usize / 8) * 32 +
+                        self.fetch_x as usize
+                } else {
+                    let background_y = gpu.lcd_y_coordinate.wrapping_add(gpu.scroll_y) as usize;
+                    let tile_x = ((gpu.scroll_x / 8) as usize + self.fetch_x as usize) % 32;
+                    tile_map_offset(&gpu.background_tile_map) + (background_y / 8) * 32 + tile_x
+                };
+                self.tile_number = gpu.video_ram[map_offset];
+                FetchStep::DataLow
+            }
+            FetchStep::DataLow => FetchStep::DataHigh,
+            _ => {
+                let row = if self.fetching_window {
+                    gpu.window_line as usize % 8
+                } else {
+                    gpu.lcd_y_coordinate.wrapping_add(gpu.scroll_y) as usize % 8
+                };
+                self.tile_row = gpu.tile_set[gpu.tile_set_index(self.tile_number)][row];
+                FetchStep::Push
+            }
+        };
+    }
+
+    /* Objects are merged into the FIFO in priority order, so pixels already
+     * taken by an earlier object are only replaced where they are transparent
+     */
+    fn merge_object(&mut self, gpu: &GPU) {
+        let obj = gpu.obj_data[self.line_objects[self.next_object]];
+        self.next_object += 1;
+
+        let tile_row = gpu.object_tile_row(&obj);
+        //Objects partly off the left edge lose the pixels that are off screen
+        let skip = std::cmp::max(self.x as i16 - obj.x, 0) as usize;
+        for x in skip..8 {
+            let value = tile_row[if obj.flip_x { 7 - x } else { x }];
+            let pixel = ObjectPixel {
+                value,
+                palette: obj.palette,
+                behind_background: obj.priority,
+            };
+            match self.objects.get_mut(x - skip) {
+                Some(existing) => {
+                    if existing.value == TilePixelValue::Zero {
+                        *existing = pixel;
+                    }
+                }
+                None => self.objects.push_back(pixel),
+            }
+        }
+    }
+
+    fn draw_pixel(&self, gpu: &mut GPU, value: TilePixelValue, object: Option<ObjectPixel>) {
+        let background = if gpu.background_display_enabled {
+            value
+        } else {
+            TilePixelValue::Zero
+        };
+
+        let color = match object {
+            Some(object) if object.value != TilePixelValue::Zero &&
+                gpu.obj_display_enable &&
+                (!object.behind_background || background == TilePixelValue::Zero) => {
+                gpu.tile_value_to_object_color(object.palette, &object.value)
+            }
+            _ if gpu.background_display_enabled => gpu.tile_value_to_background_color(&background),
+            _ => Color::White,
+        };
+        gpu.write_screen_pixel(self.x as usize, color);
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.background.len() as u8);
+        for value in self.background.iter() {
+            state.write_u8(tile_pixel_to_u8(*value));
+        }
+        state.write_u8(self.objects.len() as u8);
+        for pixel in self.objects.iter() {
+            state.write_u8(tile_pixel_to_u8(pixel.value));
+            state.write_bool(pixel.palette == ObjectPalette::One);
+            state.write_bool(pixel.behind_background);
+        }
+
+        state.write_u8(match self.step {
+            FetchStep::TileNumber => 0,
+            FetchStep::DataLow => 1,
+            FetchStep::DataHigh => 2,
+            FetchStep::Push => 3,
+        });
+        state.write_u8(self.step_cycles);
+        state.write_u8(self.fetch_x);
+        state.write_u8(self.tile_number);
+        for value in self.tile_row.iter() {
+            state.write_u8(tile_pixel_to_u8(*value));
+        }
+        state.write_bool(self.fetching_window);
+
+        state.write_u8(self.start_cycles);
+        state.write_u8(self.object_cycles);
+        state.write_u8(self.discard);
+        state.write_u8(self.x);
+
+        state.write_u8(self.line_objects.len() as u8);
+        for index in self.line_objects.iter() {
+            state.write_u8(*index as u8);
+        }
+        state.write_u8(self.next_object as u8);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.background.clear();
+        for _ in 0..state.read_u8()? {
+            self.background.push_back(tile_pixel_from_u8(state.read_u8()?)?);
+        }
+        self.objects.clear();
+        for _ in 0..state.read_u8()? {
+            let value = tile_pixel_from_u8(state.read_u8()?)?;
+            let palette = if state.read_bool()? { ObjectPalette::One } else { ObjectPalette::Zero };
+            let behind_background = state.read_bool()?;
+            self.objects.push_back(ObjectPixel { value, palette, behind_background });
+        }
+
+        self.step = match state.read_u8()? {
+            0 => FetchStep::TileNumber,
+            1 => FetchStep::DataLow,
+            2 => FetchStep::DataHigh,
+            3 => FetchStep::Push,
+            value => return Err(StateError::InvalidValue("fetcher step", value)),
+        };
+        self.step_cycles = state.read_u8()?;
+        self.fetch_x = state.read_u8()?;
+        self.tile_number = state.read_u8()?;
+        for value in self.tile_row.iter_mut() {
+            *value = tile_pixel_from_u8(state.read_u8()?)?;
+        }
+        self.fetching_window = state.read_bool()?;
+
+        self.start_cycles = state.read_u8()?;
+        self.object_cycles = state.read_u8()?;
+        self.discard = state.read_u8()?;
+        self.x = state.read_u8()?;
+        if self.x as usize > SCREEN_WIDTH {
+            return Err(StateError::InvalidValue("pixel FIFO position", self.x));
+        }
+
+        self.line_objects.clear();
+        for _ in 0..state.read_u8()? {
+            let index = state.read_u8()?;
+            if index as usize >= super::OAM_NUMBER_OF_OBJECTS {
+                return Err(StateError::InvalidValue("object index", index));
+            }
+            self.line_objects.push(index as usize);
+        }
+        self.next_object = state.read_u8()? as usize;
+        Ok(())
+    }
+}
+
+fn tile_pixel_to_u8(value: TilePixelValue) -> u8 {
+    match value {
+        TilePixelValue::Zero => 0,
+        TilePixelValue::One => 1,
+        TilePixelValue::Two => 2,
+        TilePixelValue::Three => 3,
+    }
+}
+
+fn tile_pixel_from_u8(value: u8) -> Result<TilePixelValue, StateError> {
+    match value {
+        0 => Ok(TilePixelValue::Zero),
+        1 => Ok(TilePixelValue::One),
+        2 => Ok(TilePixelValue::Two),
+        3 => Ok(TilePixelValue::Three),
+        _ => Err(StateError::InvalidValue("pixel value", value)),
+    }
+}
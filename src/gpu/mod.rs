@@ -0,0 +1,1271 @@
+mod fifo;
+
+use self::fifo::PixelFifo;
+use crate::memory_bus::VIDEO_RAM_SIZE;
+use crate::memory_bus::VIDEO_RAM_START;
+use crate::memory_bus::OAM_SIZE;
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+pub const SCREEN_PIXEL_COUNT: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
+pub const ONE_FRAME_IN_CYCLES: usize = 70224;
+
+pub const OAM_NUMBER_OF_OBJECTS: usize = 40;
+const OBJECTS_PER_LINE: usize = 10;
+
+const OAM_SCAN_CYCLES: u16 = 80;
+const LINE_CYCLES: u16 = 456;
+const LINE_153_CYCLES: u16 = 4; //LY reads 153 only briefly, then 0 for the rest of the line
+const FIRST_LINE_SHORTENED_CYCLES: u16 = 4; //the first line after turning the LCD on is shorter
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Renderer {
+    Scanline,  //draws a whole line at the end of mode 3, fixed mode lengths
+    PixelFifo, //models the fetchers and pixel FIFOs dot by dot, variable mode 3 length
+}
+
+#[derive(PartialEq)]
+pub enum TileData {
+    Ox8000,
+    Ox8800,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TileMap {
+    Ox9800,
+    Ox9C00,
+}
+
+//Maps tile value to a palette value
+#[derive(Copy, Clone, PartialEq)]
+pub enum TilePixelValue {
+    Zero,
+    One,
+    Two,
+    Three
+}
+
+impl Default for TilePixelValue {
+    fn default() -> Self {
+        TilePixelValue::Zero
+    }
+}
+
+type TileRow = [TilePixelValue; 8];
+type Tile = [TileRow; 8];
+
+fn empty_tile() -> Tile {
+    [[Default::default(); 8]; 8]
+}
+
+//Offset of a tile map into video RAM
+fn tile_map_offset(tile_map: &TileMap) -> usize {
+    let address = match tile_map {
+        TileMap::Ox9800 => 0x9800,
+        TileMap::Ox9C00 => 0x9C00,
+    };
+    address - VIDEO_RAM_START
+}
+
+
+#[derive(PartialEq)]
+pub enum ObjSize {
+    Size8x8,
+    Size8x16,
+}
+
+#[derive(Copy, Clone)]
+pub struct ObjectData {
+    x: i16,
+    y: i16,
+    tile: u8,
+    priority: bool,
+    flip_x: bool,
+    flip_y: bool,
+    palette: ObjectPalette
+}
+
+impl Default for ObjectData {
+    fn default() -> Self {
+        ObjectData {
+            x: -16,
+            y: -8,
+            tile: Default::default(),
+            palette: Default::default(),
+            flip_x: Default::default(),
+            flip_y: Default::default(),
+            priority: Default::default(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Mode {
+    HBlank,
+    VBlank,
+    OAMAccess,
+    VRAMAccess, //OAM search 20 clocks
+                //pixel transfer 43+ clocks
+                //h-blank 51-pixeltransfer clocks
+                //v-blank 10 clocks
+}
+
+#[derive(Copy, Clone)]
+pub enum Color {
+    White = 255,
+    LightGray = 192,
+    DarkGray = 96,
+    Black = 0,
+}
+
+impl std::convert::From<u8> for Color {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Color::White,
+            1 => Color::LightGray,
+            2 => Color::DarkGray,
+            3 => Color::Black,
+            _ => panic!("Failed to determine palette color: {}", value),
+        }
+    }
+}
+
+impl std::convert::From<Color> for u8 {
+    fn from(color: Color) -> u8 {
+        match color {
+            Color::White => 0,
+            Color::LightGray => 1,
+            Color::DarkGray => 2,
+            Color::Black => 3,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum ObjectPalette {
+    Zero,
+    One
+}
+
+impl Default for ObjectPalette {
+    fn default() -> Self {
+        ObjectPalette::Zero
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Palette(pub Color, pub Color, pub Color, pub Color);
+impl Palette {
+    fn new() -> Palette {
+        Palette(
+            Color::White,
+            Color::LightGray,
+            Color::DarkGray,
+            Color::Black,
+        )
+    }
+}
+impl std::convert::From<Palette> for u8 {
+    fn from(palette: Palette) -> u8 {
+        u8::from(palette.0) |
+        u8::from(palette.1) << 2 |
+        u8::from(palette.2) << 4 |
+        u8::from(palette.3) << 6
+    }
+}
+
+impl std::convert::From<u8> for Palette {
+    fn from(value: u8) -> Self {
+        Palette(
+            (value & 0b11).into(),
+            ((value >> 2) & 0b11).into(),
+            ((value >> 4) & 0b11).into(),
+            (value >> 6).into(),
+        )
+    }
+}
+
+pub struct GPU {
+    /* Display data
+     * 160 x144 pixels on screen, background map is 256x256
+     * 4 shades of gray
+     * 8x8 pixel tile based, 20x18 tiles on screen show, 32x32 tiles in vram(background map)
+     * 256 tiles in total,
+     * 40 sprites (10 per line)
+     * 8 KB VRAM
+     * has different layers: background, window, sprite
+     */
+
+    /* VRAM memory map
+     * 1KB window map 32x32 index
+     * 1KB BG map 32x32 index
+     * 4KB BG Tiles 256 x 16 bytes
+     * 4KB sprite tiles 256 x 16 bytes
+     * cant fit all so has different modes
+     */
+
+    /* OAM Entry 4 bytes in RAM
+     * position x
+     * position y
+     * tile number
+     * priority
+     * flip x
+     * flip y
+     * palette
+     */
+    pub screen_buffer: [u8; SCREEN_WIDTH * SCREEN_HEIGHT * 4],
+    pub video_ram: [u8; VIDEO_RAM_SIZE],
+    pub oam: [u8; OAM_SIZE],
+    cycles: u16, //cycles spent in the current mode, or the current line with the pixel FIFO
+    renderer: Renderer,
+    fifo: PixelFifo,
+    restrict_cpu_access: bool, //block VRAM and OAM during the modes that use them, like hardware
+
+
+    pub background_window_tile_data: TileData, // location of tile data for background and window
+    pub background_tile_map: TileMap,          //background tile map location
+    pub background_display_enabled: bool,      //background display status
+    pub background_window_palette: Palette,
+
+    pub tile_set: [Tile; 384],
+
+    pub obj_size: ObjSize,        //Size of objs (sprites)
+    pub obj_display_enable: bool, //Obj display status
+    pub obj_0_palette: Palette,
+    pub obj_1_palette: Palette,
+    pub obj_data: [ObjectData; OAM_NUMBER_OF_OBJECTS],
+
+    //LCD Control
+    pub lcd_display_enabled: bool, //LCD is complete on/off
+    first_line: bool, //first line after the LCD was turned on, mode 0 instead of the OAM scan
+    blank_frame: bool, //first frame after the LCD was turned on isn't displayed
+    pub lcd_y_compare: u8,
+    pub lyc_interrupt_enabled: bool,
+    pub oam_interrupt_enabled: bool,
+    pub vblank_interrupt_enabled: bool,
+    pub hblank_interrupt_enabled: bool,
+    pub coincidence_flag: bool,
+    stat_line: bool, //the enabled STAT sources ORed together
+    last_line: bool, //line 153 after LY has already gone back to 0
+    pub lcd_y_coordinate: u8, //current line being drawn
+    pub lcd_mode: Mode, // LCD current mode
+
+    pub window_tile_map: TileMap, //Window tile map location
+    pub window_display_enabled: bool,
+    pub window_x: u8,
+    pub window_y: u8,
+    window_line: u8, //internal line counter, only advances on lines showing the window
+    window_y_triggered: bool, //LY has matched WY during this frame
+
+    pub scroll_x: u8,
+    pub scroll_y: u8,
+}
+
+impl GPU {
+    pub fn new() -> GPU {
+        GPU::with_options(Renderer::Scanline, false)
+    }
+
+    pub fn with_options(renderer: Renderer, restrict_cpu_access: bool) -> GPU {
+        GPU {
+            screen_buffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT * 4],
+            video_ram: [0xFF; VIDEO_RAM_SIZE],
+            oam: [0xFF; OAM_SIZE],
+            cycles: 0,
+            renderer,
+            fifo: Default::default(),
+            restrict_cpu_access,
+
+            background_window_tile_data: TileData::Ox8000,
+            background_tile_map: TileMap::Ox9800,
+            background_display_enabled: false,
+            background_window_palette: Palette::new(),
+
+            tile_set: [empty_tile(); 384],
+
+            obj_size: ObjSize::Size8x8,
+            obj_display_enable: false,
+            obj_0_palette: Palette::new(),
+            obj_1_palette: Palette::new(),
+            obj_data: [Default::default(); OAM_NUMBER_OF_OBJECTS],
+
+            lcd_display_enabled: false,
+            first_line: false,
+            blank_frame: false,
+            lcd_y_compare: 0,
+            lyc_interrupt_enabled: false,
+            vblank_interrupt_enabled: false,
+            hblank_interrupt_enabled: false,
+            oam_interrupt_enabled: false,
+            coincidence_flag: false,
+            stat_line: false,
+            last_line: false,
+            lcd_y_coordinate: 0,
+            lcd_mode: Mode::HBlank,
+
+            window_tile_map: TileMap::Ox9800,
+            window_display_enabled: false,
+            window_x: 0,
+            window_y: 0,
+            window_line: 0,
+            window_y_triggered: false,
+            scroll_x: 0,
+            scroll_y: 0,
+        }
+    }
+
+    pub fn renderer(&self) -> Renderer {
+        self.renderer
+    }
+
+    /* The PPU reads VRAM during mode 3 and OAM during modes 2 and 3, the CPU can't
+     * access them then. Only enforced with restrict_cpu_access, otherwise the CPU
+     * always gets through. With the LCD off the mode stays 0.
+     */
+    pub fn cpu_may_access_vram(&self) -> bool {
+        match self.lcd_mode {
+            Mode::VRAMAccess => !self.restrict_cpu_access,
+            _ => true,
+        }
+    }
+
+    pub fn cpu_may_access_oam(&self) -> bool {
+        match self.lcd_mode {
+            Mode::OAMAccess | Mode::VRAMAccess => !self.restrict_cpu_access,
+            _ => true,
+        }
+    }
+
+    /* LCDC bit 7
+     * Turning the LCD off resets LY and the mode and the screen goes white.
+     * Turning it back on starts line 0 without an OAM scan, and the frame drawn after
+     * that isn't shown.
+     */
+    pub fn set_lcd_enabled(&mut self, enabled: bool) {
+        if enabled == self.lcd_display_enabled {
+            return;
+        }
+        self.lcd_display_enabled = enabled;
+
+        self.lcd_y_coordinate = 0;
+        self.lcd_mode = Mode::HBlank;
+        self.stat_line = false;
+        self.last_line = false;
+        self.window_line = 0;
+        self.window_y_triggered = false;
+        self.fifo = PixelFifo::default();
+        if enabled {
+            self.cycles = FIRST_LINE_SHORTENED_CYCLES;
+            self.first_line = true;
+            self.blank_frame = true;
+        } else {
+            self.cycles = 0;
+            self.first_line = false;
+            self.blank_frame = false;
+            self.screen_buffer = [255; SCREEN_WIDTH * SCREEN_HEIGHT * 4];
+        }
+    }
+
+    pub fn step(&mut self, cycles: u16) -> (bool, bool) {
+        if !self.lcd_display_enabled {
+            return (false, false);
+        }
+
+        match self.renderer {
+            Renderer::Scanline => self.step_scanline(cycles),
+            Renderer::PixelFifo => self.step_pixel_fifo(cycles),
+        }
+    }
+
+    fn step_scanline(&mut self, cycles: u16) -> (bool, bool) {
+        let mut vblank_interrupt = false;
+
+        self.cycles += cycles;
+
+        let mode = self.lcd_mode;
+        match mode {
+            Mode::HBlank if self.first_line => {
+                if self.cycles >= OAM_SCAN_CYCLES {
+                    self.first_line = false;
+                    self.lcd_mode = Mode::VRAMAccess;
+                    self.cycles %= OAM_SCAN_CYCLES;
+                }
+            },
+            Mode::OAMAccess => {
+                if self.cycles >= 80 {
+                    self.lcd_mode = Mode::VRAMAccess;
+                    self.cycles = self.cycles % 80;
+                }
+            },
+            Mode::VRAMAccess => {
+                if self.cycles >= 172 {
+                    self.cycles = self.cycles % 172;
+                    self.lcd_mode = Mode::HBlank;
+                    self.render_scanline();
+                }
+            },
+            Mode::HBlank => {
+                if self.cycles >= 200 {
+                    self.cycles = self.cycles % 200;
+                    vblank_interrupt = self.next_line();
+                }
+            },
+            Mode::VBlank => {
+                if self.cycles >= LINE_CYCLES {
+                    self.cycles %= LINE_CYCLES;
+                    vblank_interrupt = self.next_line();
+                }
+                self.check_line_153();
+            }
+        }
+
+        (vblank_interrupt, self.update_stat_line())
+    }
+
+    fn step_pixel_fifo(&mut self, cycles: u16) -> (bool, bool) {
+        let mut vblank_interrupt = false;
+        let mut lcd_c_interrupt = false;
+
+        //The FIFO needs the rest of the GPU while it runs
+        let mut fifo = std::mem::take(&mut self.fifo);
+        for _ in 0..cycles {
+            self.cycles += 1;
+            match self.lcd_mode {
+                Mode::OAMAccess => {
+                    if self.cycles >= OAM_SCAN_CYCLES {
+                        self.lcd_mode = Mode::VRAMAccess;
+                        fifo.start_line(self);
+                    }
+                }
+                Mode::VRAMAccess => {
+                    //Mode 3 ends when the line is drawn, HBlank gets what is left of the line
+                    if fifo.tick(self) {
+                        self.lcd_mode = Mode::HBlank;
+                    }
+                }
+                Mode::HBlank if self.first_line => {
+                    if self.cycles >= OAM_SCAN_CYCLES {
+                        self.first_line = false;
+                        self.lcd_mode = Mode::VRAMAccess;
+                        fifo.start_line(self);
+                    }
+                }
+                Mode::HBlank | Mode::VBlank => {
+                    if self.cycles >= LINE_CYCLES {
+                        self.cycles = 0;
+                        vblank_interrupt |= self.next_line();
+                    }
+                    if self.lcd_mode == Mode::VBlank {
+                        self.check_line_153();
+                    }
+                }
+            }
+            lcd_c_interrupt |= self.update_stat_line();
+        }
+        self.fifo = fifo;
+
+        (vblank_interrupt, lcd_c_interrupt)
+    }
+
+    /* Moves on to the next line, returns true when VBlank starts */
+    fn next_line(&mut self) -> bool {
+        if self.last_line {
+            //LY is already 0
+            self.last_line = false;
+            self.window_line = 0;
+            self.window_y_triggered = false;
+        } else {
+            self.lcd_y_coordinate += 1;
+        }
+
+        if self.lcd_y_coordinate == 144 {
+            self.lcd_mode = Mode::VBlank;
+            self.blank_frame = false;
+            return true;
+        }
+        if self.lcd_y_coordinate < 144 {
+            self.lcd_mode = Mode::OAMAccess;
+        }
+        false
+    }
+
+    /* LY goes back to 0 a few dots into line 153, so LYC=0 matches during line 153 already */
+    fn check_line_153(&mut self) {
+        if self.lcd_y_coordinate == 153 && self.cycles >= LINE_153_CYCLES {
+            self.lcd_y_coordinate = 0;
+            self.last_line = true;
+        }
+    }
+
+    /* The STAT interrupt sources are ORed into a single line and the interrupt is only
+     * requested on its rising edge. While one source holds the line high the others
+     * can't cause another interrupt (STAT blocking).
+     * Returns true when the line goes high.
+     */
+    fn update_stat_line(&mut self) -> bool {
+        self.check_line_coincidence();
+        let line = (self.lyc_interrupt_enabled && self.coincidence_flag) ||
+            match self.lcd_mode {
+                Mode::HBlank => self.hblank_interrupt_enabled,
+                Mode::VBlank => self.vblank_interrupt_enabled,
+                Mode::OAMAccess => self.oam_interrupt_enabled,
+                Mode::VRAMAccess => false,
+            };
+        let rising_edge = line && !self.stat_line;
+        self.stat_line = line;
+        rising_edge
+    }
+
+    /* STAT write, returns true when it requests an interrupt.
+     * On the DMG the write acts as if the HBlank, VBlank and LY=LYC sources were all
+     * enabled for a cycle, which raises the line in modes 0 and 1 or when LY=LYC.
+     */
+    pub fn write_stat(&mut self, byte: u8) -> bool {
+        let mut interrupt = false;
+        if self.lcd_display_enabled {
+            self.lyc_interrupt_enabled = true;
+            self.vblank_interrupt_enabled = true;
+            self.hblank_interrupt_enabled = true;
+            interrupt = self.update_stat_line();
+        }
+
+        self.lyc_interrupt_enabled = ((byte >> 6) & 0b1) == 1;
+        self.oam_interrupt_enabled = ((byte >> 5) & 0b1) == 1;
+        self.vblank_interrupt_enabled = ((byte >> 4) & 0b1) == 1;
+        self.hblank_interrupt_enabled = ((byte >> 3) & 0b1) == 1;
+
+        if self.lcd_display_enabled {
+            interrupt |= self.update_stat_line();
+        }
+        interrupt
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.screen_buffer);
+        state.write_bytes(&self.video_ram);
+        state.write_bytes(&self.oam);
+        state.write_u16(self.cycles);
+        self.fifo.save_state(state);
+
+        state.write_bool(self.background_window_tile_data == TileData::Ox8000);
+        state.write_bool(self.background_tile_map == TileMap::Ox9C00);
+        state.write_bool(self.background_display_enabled);
+        state.write_u8(self.background_window_palette.into());
+
+        state.write_bool(self.obj_size == ObjSize::Size8x16);
+        state.write_bool(self.obj_display_enable);
+        state.write_u8(self.obj_0_palette.into());
+        state.write_u8(self.obj_1_palette.into());
+
+        state.write_bool(self.lcd_display_enabled);
+        state.write_bool(self.first_line);
+        state.write_bool(self.blank_frame);
+        state.write_u8(self.lcd_y_compare);
+        state.write_bool(self.lyc_interrupt_enabled);
+        state.write_bool(self.oam_interrupt_enabled);
+        state.write_bool(self.vblank_interrupt_enabled);
+        state.write_bool(self.hblank_interrupt_enabled);
+        state.write_bool(self.coincidence_flag);
+        state.write_bool(self.stat_line);
+        state.write_bool(self.last_line);
+        state.write_u8(self.lcd_y_coordinate);
+        state.write_u8(match self.lcd_mode {
+            Mode::HBlank => 0,
+            Mode::VBlank => 1,
+            Mode::OAMAccess => 2,
+            Mode::VRAMAccess => 3,
+        });
+
+        state.write_bool(self.window_tile_map == TileMap::Ox9C00);
+        state.write_bool(self.window_display_enabled);
+        state.write_u8(self.window_x);
+        state.write_u8(self.window_y);
+        state.write_u8(self.window_line);
+        state.write_bool(self.window_y_triggered);
+
+        state.write_u8(self.scroll_x);
+        state.write_u8(self.scroll_y);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_bytes(&mut self.screen_buffer)?;
+        let mut video_ram = [0; VIDEO_RAM_SIZE];
+        state.read_bytes(&mut video_ram)?;
+        let mut oam = [0; OAM_SIZE];
+        state.read_bytes(&mut oam)?;
+        //The decoded tile set and object data are rebuilt from VRAM and OAM
+        for (address, value) in video_ram.iter().enumerate() {
+            self.write_vram(address, *value);
+        }
+        for (index, value) in oam.iter().enumerate() {
+            self.write_oam(index, *value);
+        }
+        self.cycles = state.read_u16()?;
+        self.fifo.load_state(state)?;
+
+        self.background_window_tile_data = if state.read_bool()? {
+            TileData::Ox8000
+        } else {
+            TileData::Ox8800
+        };
+        self.background_tile_map = if state.read_bool()? {
+            TileMap::Ox9C00
+        } else {
+            TileMap::Ox9800
+        };
+        self.background_display_enabled = state.read_bool()?;
+        self.background_window_palette = state.read_u8()?.into();
+
+        self.obj_size = if state.read_bool()? {
+            ObjSize::Size8x16
+        } else {
+            ObjSize::Size8x8
+        };
+        self.obj_display_enable = state.read_bool()?;
+        self.obj_0_palette = state.read_u8()?.into();
+        self.obj_1_palette = state.read_u8()?.into();
+
+        self.lcd_display_enabled = state.read_bool()?;
+        self.first_line = state.read_bool()?;
+        self.blank_frame = state.read_bool()?;
+        self.lcd_y_compare = state.read_u8()?;
+        self.lyc_interrupt_enabled = state.read_bool()?;
+        self.oam_interrupt_enabled = state.read_bool()?;
+        self.vblank_interrupt_enabled = state.read_bool()?;
+        self.hblank_interrupt_enabled = state.read_bool()?;
+        self.coincidence_flag = state.read_bool()?;
+        self.stat_line = state.read_bool()?;
+        self.last_line = state.read_bool()?;
+        self.lcd_y_coordinate = state.read_u8()?;
+        self.lcd_mode = match state.read_u8()? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OAMAccess,
+            3 => Mode::VRAMAccess,
+            value => return Err(StateError::InvalidValue("LCD mode", value)),
+        };
+
+        self.window_tile_map = if state.read_bool()? {
+            TileMap::Ox9C00
+        } else {
+            TileMap::Ox9800
+        };
+        self.window_display_enabled = state.read_bool()?;
+        self.window_x = state.read_u8()?;
+        self.window_y = state.read_u8()?;
+        self.window_line = state.read_u8()?;
+        self.window_y_triggered = state.read_bool()?;
+
+        self.scroll_x = state.read_u8()?;
+        self.scroll_y = state.read_u8()?;
+        Ok(())
+    }
+
+    pub fn check_line_coincidence(&mut self) -> bool {
+        self.coincidence_flag = self.lcd_y_coordinate == self.lcd_y_compare;
+        return self.coincidence_flag
+    }
+
+    pub fn write_vram(&mut self, address: usize, value: u8) {
+        self.video_ram[address] = value;
+        if address >= 0x1800 {
+            return;
+        }
+
+        //tile background maps
+        let index = address;
+        let normalized_index = index & 0xFFFE;
+        let first_byte = self.video_ram[normalized_index];
+        let second_byte = self.video_ram[normalized_index + 1];
+
+        let tile_index = index / 16;
+        let row_index = (index % 16) / 2;
+
+        for pixel_index in 0..8 {
+            let mask = 1 << (7 - pixel_index);
+            let lsb = first_byte & mask;
+            let msb = second_byte & mask;
+
+            let value = match (lsb != 0, msb != 0) {
+                (true, true) => TilePixelValue::Three,
+                (false, true) => TilePixelValue::Two,
+                (true, false) => TilePixelValue::One,
+                (false, false) => TilePixelValue::Zero
+            };
+
+            self.tile_set[tile_index][row_index][pixel_index] = value;
+        }
+    }
+
+    pub fn write_oam(&mut self, index: usize, value: u8) {
+        self.oam[index] = value;
+        let object_index = index / 4;
+        if object_index > OAM_NUMBER_OF_OBJECTS {
+            return;
+        }
+
+        let byte = index % 4;
+
+        let mut data = self.obj_data.get_mut(object_index).unwrap();
+        match byte {
+            0 => data.y = (value as i16) - 0x10,
+            1 => data.x = (value as i16) - 0x8,
+            2 => data.tile = value,
+            _ => {
+                data.palette = if (value & 0x10) != 0 {
+                    ObjectPalette::One
+                }
+                else {
+                    ObjectPalette::Zero
+                };
+                data.flip_x = (value & 0x20) != 0;
+                data.flip_y = (value & 0x40) != 0;
+                data.priority = (value & 0x80) != 0;
+            }
+        }
+
+    }
+
+
+    fn render_scanline(&mut self) {
+        let mut scanline: [TilePixelValue; SCREEN_WIDTH] = [Default::default(); SCREEN_WIDTH];
+
+        if self.background_display_enabled {
+            let background_y = self.lcd_y_coordinate.wrapping_add(self.scroll_y) as usize;
+            let tile_map_offset = tile_map_offset(&self.background_tile_map) + (background_y / 8) * 32;
+
+            for line_x in 0..SCREEN_WIDTH {
+                //The 256x256 background wraps around in both directions
+                let background_x = (line_x as u8).wrapping_add(self.scroll_x) as usize;
+                let tile_number = self.video_ram[tile_map_offset + background_x / 8];
+                let tile_value = self.tile_set
+                    [self.tile_set_index(tile_number)]
+                    [background_y % 8]
+                    [background_x % 8];
+
+                let color = self.tile_value_to_background_color(&tile_value);
+                self.write_screen_pixel(line_x, color);
+                scanline[line_x] = tile_value;
+            }
+        } else {
+            for line_x in 0..SCREEN_WIDTH {
+                self.write_screen_pixel(line_x, Color::White);
+            }
+        }
+
+        if self.lcd_y_coordinate == self.window_y {
+            self.window_y_triggered = true;
+        }
+        //On DMG the background enable bit turns off the window as well
+        if self.window_display_enabled && self.background_display_enabled {
+            self.render_window_line(&mut scanline);
+        }
+
+        if self.obj_display_enable {
+            self.render_objects_line(&scanline);
+        }
+    }
+
+    fn render_window_line(&mut self, scanline: &mut [TilePixelValue; SCREEN_WIDTH]) {
+        if !self.window_y_triggered || self.window_x > 166 {
+            return;
+        }
+
+        //WX is the window position plus 7, below 7 the left edge of the window is cut off
+        let window_start = self.window_x as i16 - 7;
+        let window_y = self.window_line as usize;
+        let tile_map_offset = tile_map_offset(&self.window_tile_map) + (window_y / 8) * 32;
+
+        let start = std::cmp::max(window_start, 0) as usize;
+        for (line_x, pixel) in scanline.iter_mut().enumerate().skip(start) {
+            let window_x = (line_x as i16 - window_start) as usize;
+            let tile_number = self.video_ram[tile_map_offset + window_x / 8];
+            let tile_value = self.tile_set
+                [self.tile_set_index(tile_number)]
+                [window_y % 8]
+                [window_x % 8];
+
+            let color = self.tile_value_to_background_color(&tile_value);
+            self.write_screen_pixel(line_x, color);
+            *pixel = tile_value;
+        }
+        self.window_line += 1;
+    }
+
+    fn object_height(&self) -> i16 {
+        match self.obj_size {
+            ObjSize::Size8x8 => 8,
+            ObjSize::Size8x16 => 16
+        }
+    }
+
+    /* OAM scan, picks the first 10 objects covering this line, X is not considered.
+     * Returned in drawing priority, the lowest X wins and then the lowest OAM index
+     */
+    fn line_objects(&self) -> Vec<usize> {
+        let line = self.lcd_y_coordinate as i16;
+        let object_height = self.object_height();
+        let mut objects: Vec<usize> = (0..OAM_NUMBER_OF_OBJECTS)
+            .filter(|index| {
+                let obj = &self.obj_data[*index];
+                obj.y <= line && line < obj.y + object_height
+            })
+            .take(OBJECTS_PER_LINE)
+            .collect();
+        objects.sort_by_key(|index| (self.obj_data[*index].x, *index));
+        objects
+    }
+
+    fn object_tile_row(&self, obj: &ObjectData) -> TileRow {
+        let object_height = self.object_height();
+        let mut row = self.lcd_y_coordinate as i16 - obj.y;
+        if obj.flip_y {
+            row = object_height - 1 - row;
+        }
+        //8x16 objects ignore the low bit of the tile number
+        let tile = if object_height == 16 {
+            (obj.tile & 0xFE) + (row / 8) as u8
+        } else {
+            obj.tile
+        };
+        self.tile_set[tile as usize][(row % 8) as usize]
+    }
+
+    fn render_objects_line(&mut self, scanline: &[TilePixelValue; SCREEN_WIDTH]) {
+        let objects = self.line_objects();
+
+        let mut pixels: [Option<(TilePixelValue, ObjectPalette, bool)>; SCREEN_WIDTH] =
+            [None; SCREEN_WIDTH];
+        for index in objects.iter() {
+            let obj = self.obj_data[*index];
+            let tile_row = self.object_tile_row(&obj);
+
+            for x in 0..8i16 {
+                let screen_x = obj.x + x;
+                if screen_x < 0 || screen_x >= SCREEN_WIDTH as i16 {
+                    continue;
+                }
+                let value = tile_row[if obj.flip_x { 7 - x } else { x } as usize];
+                let pixel = &mut pixels[screen_x as usize];
+                //Colour 0 is transparent and lets lower priority objects through
+                if value != TilePixelValue::Zero && pixel.is_none() {
+                    *pixel = Some((value, obj.palette, obj.priority));
+                }
+            }
+        }
+
+        for (x, pixel) in pixels.iter().enumerate() {
+            if let Some((value, palette, behind_background)) = pixel {
+                //With the priority bit set background colours 1-3 are drawn over the object
+                if !behind_background || scanline[x] == TilePixelValue::Zero {
+                    let color = self.tile_value_to_object_color(*palette, value);
+                    self.write_screen_pixel(x, color);
+                }
+            }
+        }
+    }
+
+    /* Tile numbers from the background and window maps, in 0x8800 mode they are
+     * signed with 0 at 0x9000. The tile set holds 0x8000-0x97FF so both modes share it
+     */
+    fn tile_set_index(&self, tile_number: u8) -> usize {
+        match self.background_window_tile_data {
+            TileData::Ox8000 => tile_number as usize,
+            TileData::Ox8800 => (256 + tile_number as i8 as i16) as usize,
+        }
+    }
+
+    fn write_screen_pixel(&mut self, x: usize, color: Color) {
+        if self.blank_frame {
+            return;
+        }
+        let offset = (self.lcd_y_coordinate as usize * SCREEN_WIDTH + x) * 4;
+        self.screen_buffer[offset] = color as u8;
+        self.screen_buffer[offset + 1] = color as u8;
+        self.screen_buffer[offset + 2] = color as u8;
+        self.screen_buffer[offset + 3] = 255;
+    }
+
+    fn tile_value_to_background_color(&self, tile_value: &TilePixelValue) -> Color {
+        match tile_value {
+            TilePixelValue::Zero => self.background_window_palette.0,
+            TilePixelValue::One => self.background_window_palette.1,
+            TilePixelValue::Two => self.background_window_palette.2,
+            TilePixelValue::Three => self.background_window_palette.3
+        }
+    }
+
+    fn tile_value_to_object_color(&self, palette: ObjectPalette, tile_value: &TilePixelValue) -> Color {
+        let palette = match palette {
+            ObjectPalette::Zero => self.obj_0_palette,
+            ObjectPalette::One => self.obj_1_palette,
+        };
+        match tile_value {
+            TilePixelValue::Zero => palette.0,
+            TilePixelValue::One => palette.1,
+            TilePixelValue::Two => palette.2,
+            TilePixelValue::Three => palette.3
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen_pixel(gpu: &GPU, x: usize, y: usize) -> u8 {
+        gpu.screen_buffer[(y * SCREEN_WIDTH + x) * 4]
+    }
+
+    //Tile 0 is blank, tile 1 is solid colour 3, the window map at 0x9C00 uses tile 1
+    fn window_gpu() -> GPU {
+        let mut gpu = GPU::new();
+        for address in 0..0x2000 {
+            gpu.write_vram(address, 0x00);
+        }
+        for address in 16..32 {
+            gpu.write_vram(address, 0xFF);
+        }
+        for address in 0x1C00..0x2000 {
+            gpu.write_vram(address, 0x01);
+        }
+        gpu.background_display_enabled = true;
+        gpu.window_display_enabled = true;
+        gpu.window_tile_map = TileMap::Ox9C00;
+        gpu
+    }
+
+    #[test]
+    fn window_starts_at_wx_minus_7() {
+        let mut gpu = window_gpu();
+        gpu.window_x = 7 + 10;
+        gpu.window_y = 0;
+        gpu.render_scanline();
+        assert_eq!(screen_pixel(&gpu, 9, 0), Color::White as u8);
+        assert_eq!(screen_pixel(&gpu, 10, 0), Color::Black as u8);
+        assert_eq!(gpu.window_line, 1);
+    }
+
+    #[test]
+    fn window_below_wx_7_is_cut_off() {
+        let mut gpu = window_gpu();
+        //Blank first column of window tiles, the next tile starts 3 pixels in
+        gpu.write_vram(0x1C00, 0x00);
+        gpu.window_x = 2;
+        gpu.render_scanline();
+        assert_eq!(screen_pixel(&gpu, 2, 0), Color::White as u8);
+        assert_eq!(screen_pixel(&gpu, 3, 0), Color::Black as u8);
+    }
+
+    #[test]
+    fn signed_tile_data_addressing() {
+        let mut gpu = window_gpu();
+        gpu.window_display_enabled = false;
+        gpu.background_window_tile_data = TileData::Ox8800;
+        //Tile 0 at 0x9000 solid colour 3, tile -1 (0xFF) at 0x8FF0 colour 1
+        for address in 0x1000..0x1010 {
+            gpu.write_vram(address, 0xFF);
+        }
+        for address in (0x0FF0..0x1000).step_by(2) {
+            gpu.write_vram(address, 0xFF);
+        }
+        gpu.write_vram(0x1801, 0xFF);
+        gpu.render_scanline();
+        assert_eq!(screen_pixel(&gpu, 0, 0), Color::Black as u8);
+        assert_eq!(screen_pixel(&gpu, 8, 0), Color::LightGray as u8);
+    }
+
+    #[test]
+    fn background_uses_9c00_map_and_wraps() {
+        let mut gpu = window_gpu();
+        gpu.window_display_enabled = false;
+        gpu.background_tile_map = TileMap::Ox9C00;
+        gpu.write_vram(0x1C00, 0x00);
+        gpu.scroll_x = 252;
+        gpu.render_scanline();
+        assert_eq!(screen_pixel(&gpu, 3, 0), Color::Black as u8);
+        assert_eq!(screen_pixel(&gpu, 4, 0), Color::White as u8);
+        assert_eq!(screen_pixel(&gpu, 12, 0), Color::Black as u8);
+    }
+
+    //Tile 2 is solid colour 1, tile 3 solid colour 2
+    fn object_gpu() -> GPU {
+        let mut gpu = window_gpu();
+        gpu.window_display_enabled = false;
+        gpu.background_display_enabled = false;
+        gpu.obj_display_enable = true;
+        for address in (32..48).step_by(2) {
+            gpu.write_vram(address, 0xFF);
+        }
+        for address in (49..64).step_by(2) {
+            gpu.write_vram(address, 0xFF);
+        }
+        gpu
+    }
+
+    fn place_object(gpu: &mut GPU, index: usize, x: u8, y: u8, tile: u8, flags: u8) {
+        gpu.write_oam(index * 4, y);
+        gpu.write_oam(index * 4 + 1, x);
+        gpu.write_oam(index * 4 + 2, tile);
+        gpu.write_oam(index * 4 + 3, flags);
+    }
+
+    #[test]
+    fn ten_objects_per_line() {
+        let mut gpu = object_gpu();
+        for index in 0..11 {
+            place_object(&mut gpu, index, 8 + index as u8 * 8, 16, 1, 0);
+        }
+        gpu.render_scanline();
+        assert_eq!(screen_pixel(&gpu, 9 * 8, 0), Color::Black as u8);
+        assert_eq!(screen_pixel(&gpu, 10 * 8, 0), Color::White as u8);
+    }
+
+    #[test]
+    fn lower_x_wins_then_oam_index() {
+        let mut gpu = object_gpu();
+        gpu.obj_0_palette = 0b11_10_01_00.into();
+        place_object(&mut gpu, 0, 12, 16, 2, 0);
+        place_object(&mut gpu, 1, 10, 16, 3, 0);
+        place_object(&mut gpu, 2, 30, 16, 3, 0);
+        place_object(&mut gpu, 3, 30, 16, 2, 0);
+        gpu.render_scanline();
+        assert_eq!(screen_pixel(&gpu, 5, 0), Color::DarkGray as u8);
+        assert_eq!(screen_pixel(&gpu, 22, 0), Color::DarkGray as u8);
+    }
+
+    #[test]
+    fn object_palette_and_background_priority() {
+        let mut gpu = object_gpu();
+        gpu.background_display_enabled = true;
+        gpu.obj_1_palette = 0b00_00_11_00.into();
+        //Background map is tile 0 (colour 0) apart from the second tile
+        gpu.write_vram(0x1801, 0x01);
+        place_object(&mut gpu, 0, 8, 16, 2, 0x90);
+        place_object(&mut gpu, 1, 16, 16, 2, 0x90);
+        gpu.render_scanline();
+        assert_eq!(screen_pixel(&gpu, 0, 0), Color::Black as u8);
+        assert_eq!(screen_pixel(&gpu, 8, 0), Color::Black as u8);
+        assert_eq!(screen_pixel(&gpu, 9, 0), Color::Black as u8);
+
+        gpu.obj_1_palette = 0b00_00_01_00.into();
+        gpu.render_scanline();
+        assert_eq!(screen_pixel(&gpu, 0, 0), Color::LightGray as u8);
+        assert_eq!(screen_pixel(&gpu, 8, 0), Color::Black as u8);
+    }
+
+    #[test]
+    fn tall_objects_ignore_low_tile_bit() {
+        let mut gpu = object_gpu();
+        gpu.obj_size = ObjSize::Size8x16;
+        gpu.obj_0_palette = 0b11_10_01_00.into();
+        place_object(&mut gpu, 0, 8, 16, 3, 0);
+        gpu.lcd_y_coordinate = 0;
+        gpu.render_scanline();
+        assert_eq!(screen_pixel(&gpu, 0, 0), Color::LightGray as u8);
+        gpu.lcd_y_coordinate = 8;
+        gpu.render_scanline();
+        assert_eq!(screen_pixel(&gpu, 0, 8), Color::DarkGray as u8);
+
+        place_object(&mut gpu, 0, 8, 16, 3, 0x40);
+        gpu.lcd_y_coordinate = 0;
+        gpu.render_scanline();
+        assert_eq!(screen_pixel(&gpu, 0, 0), Color::DarkGray as u8);
+    }
+
+    #[test]
+    fn window_line_only_counts_drawn_lines() {
+        let mut gpu = window_gpu();
+        gpu.window_y = 2;
+        gpu.window_x = 7;
+        for line in 0..4 {
+            gpu.lcd_y_coordinate = line;
+            gpu.render_scanline();
+        }
+        assert_eq!(gpu.window_line, 2);
+
+        gpu.window_x = 200;
+        gpu.lcd_y_coordinate = 4;
+        gpu.render_scanline();
+        assert_eq!(gpu.window_line, 2);
+    }
+
+    //Runs line 0 with the pixel FIFO renderer, returns the length of mode 3
+    fn run_fifo_line(gpu: &mut GPU) -> u16 {
+        gpu.renderer = Renderer::PixelFifo;
+        gpu.lcd_display_enabled = true;
+        gpu.lcd_mode = Mode::OAMAccess;
+        gpu.lcd_y_coordinate = 0;
+        gpu.cycles = 0;
+
+        let mut mode_3_cycles = 0;
+        while gpu.lcd_y_coordinate == 0 {
+            gpu.step(1);
+            if gpu.lcd_mode == Mode::VRAMAccess {
+                mode_3_cycles += 1;
+            }
+        }
+        mode_3_cycles
+    }
+
+    #[test]
+    fn fifo_mode_3_length() {
+        let mut gpu = window_gpu();
+        gpu.window_display_enabled = false;
+        assert_eq!(run_fifo_line(&mut gpu), 172);
+
+        gpu.scroll_x = 3;
+        assert_eq!(run_fifo_line(&mut gpu), 175);
+
+        gpu.scroll_x = 0;
+        gpu.obj_display_enable = true;
+        //Object on the first pixel of a tile waits for the whole background fetch
+        place_object(&mut gpu, 0, 8 + 32, 16, 0, 0);
+        assert_eq!(run_fifo_line(&mut gpu), 172 + 11);
+        //On the last pixel of a tile the background fetch is already done
+        place_object(&mut gpu, 1, 8 + 79, 16, 0, 0);
+        assert_eq!(run_fifo_line(&mut gpu), 172 + 11 + 6);
+    }
+
+    #[test]
+    fn fifo_matches_scanline_renderer() {
+        let mut gpu = object_gpu();
+        gpu.background_display_enabled = true;
+        gpu.window_display_enabled = true;
+        gpu.window_x = 7 + 100;
+        gpu.scroll_x = 13;
+        gpu.obj_0_palette = 0b11_10_01_00.into();
+        gpu.obj_1_palette = 0b00_01_10_00.into();
+        for column in 0..32 {
+            gpu.write_vram(0x1800 + column, column as u8 % 4);
+        }
+        place_object(&mut gpu, 0, 4, 16, 2, 0x00);
+        place_object(&mut gpu, 1, 30, 16, 3, 0x10);
+        place_object(&mut gpu, 2, 34, 16, 2, 0x80);
+        place_object(&mut gpu, 3, 104, 16, 3, 0x20);
+
+        gpu.render_scanline();
+        let scanline = gpu.screen_buffer[..SCREEN_WIDTH * 4].to_vec();
+        gpu.screen_buffer = [0; SCREEN_WIDTH * SCREEN_HEIGHT * 4];
+        gpu.window_line = 0;
+        gpu.window_y_triggered = false;
+        run_fifo_line(&mut gpu);
+        assert_eq!(&gpu.screen_buffer[..SCREEN_WIDTH * 4], &scanline[..]);
+        assert_eq!(gpu.window_line, 1);
+    }
+
+    #[test]
+    fn fifo_applies_palette_changes_mid_line() {
+        let mut gpu = window_gpu();
+        gpu.window_display_enabled = false;
+        gpu.renderer = Renderer::PixelFifo;
+        gpu.lcd_display_enabled = true;
+        gpu.lcd_mode = Mode::OAMAccess;
+        gpu.write_vram(0x1800, 0x01);
+        gpu.write_vram(0x1810, 0x01);
+        gpu.background_window_palette = 0b11_10_01_00.into();
+
+        //OAM scan, 6 dots for the first fetch, then one pixel per dot
+        gpu.step(OAM_SCAN_CYCLES + 6 + 64);
+        gpu.background_window_palette = 0b01_10_01_00.into();
+        gpu.step(LINE_CYCLES);
+        assert_eq!(screen_pixel(&gpu, 0, 0), Color::Black as u8);
+        assert_eq!(screen_pixel(&gpu, 128, 0), Color::LightGray as u8);
+    }
+
+    fn lcd_gpu(mode: Mode, line: u8) -> GPU {
+        let mut gpu = GPU::new();
+        gpu.lcd_display_enabled = true;
+        gpu.lcd_mode = mode;
+        gpu.lcd_y_coordinate = line;
+        gpu.lcd_y_compare = 0xFF;
+        gpu
+    }
+
+    #[test]
+    fn stat_interrupt_on_rising_edge_only() {
+        //HBlank holds the line high into mode 2, so the OAM source is blocked
+        let mut gpu = lcd_gpu(Mode::VRAMAccess, 0);
+        gpu.write_stat(0b0010_1000);
+        assert_eq!(gpu.step(172), (false, true));
+        assert_eq!(gpu.step(200), (false, false));
+        assert!(gpu.lcd_mode == Mode::OAMAccess);
+
+        let mut gpu = lcd_gpu(Mode::VRAMAccess, 0);
+        gpu.write_stat(0b0010_0000);
+        assert_eq!(gpu.step(172), (false, false));
+        assert_eq!(gpu.step(200), (false, true));
+    }
+
+    #[test]
+    fn coincidence_at_line_153() {
+        let mut gpu = lcd_gpu(Mode::VBlank, 153);
+        gpu.lcd_y_compare = 0;
+        gpu.write_stat(0b0100_0000);
+        assert_eq!(gpu.step(2), (false, false));
+        assert_eq!(gpu.lcd_y_coordinate, 153);
+        assert_eq!(gpu.step(2), (false, true));
+        assert_eq!(gpu.lcd_y_coordinate, 0);
+        assert!(gpu.coincidence_flag);
+
+        //Line 0 keeps LY=0, the line stays high
+        assert_eq!(gpu.step(452), (false, false));
+        assert!(gpu.lcd_mode == Mode::OAMAccess);
+        assert_eq!(gpu.lcd_y_coordinate, 0);
+        assert!(gpu.coincidence_flag);
+    }
+
+    #[test]
+    fn stat_write_bug() {
+        let mut gpu = lcd_gpu(Mode::HBlank, 0);
+        assert!(gpu.write_stat(0));
+        assert!(!gpu.hblank_interrupt_enabled);
+
+        let mut gpu = lcd_gpu(Mode::VRAMAccess, 0);
+        assert!(!gpu.write_stat(0));
+        gpu.lcd_y_compare = 0;
+        assert!(gpu.write_stat(0));
+    }
+
+    #[test]
+    fn lcd_off_resets_and_blanks() {
+        let mut gpu = lcd_gpu(Mode::VRAMAccess, 40);
+        gpu.screen_buffer = [0; SCREEN_WIDTH * SCREEN_HEIGHT * 4];
+        gpu.set_lcd_enabled(false);
+        assert_eq!(gpu.lcd_y_coordinate, 0);
+        assert!(gpu.lcd_mode == Mode::HBlank);
+        assert_eq!(screen_pixel(&gpu, 0, 0), Color::White as u8);
+        assert_eq!(gpu.step(456), (false, false));
+        assert_eq!(gpu.lcd_y_coordinate, 0);
+    }
+
+    #[test]
+    fn lcd_on_skips_oam_scan_and_first_frame() {
+        let mut gpu = window_gpu();
+        gpu.window_display_enabled = false;
+        gpu.oam_interrupt_enabled = true;
+        gpu.write_vram(0x1801, 0x01);
+        gpu.screen_buffer = [255; SCREEN_WIDTH * SCREEN_HEIGHT * 4];
+        gpu.set_lcd_enabled(true);
+
+        //Mode 0 instead of mode 2, 4 dots shorter
+        assert_eq!(gpu.step(75), (false, false));
+        assert!(gpu.lcd_mode == Mode::HBlank);
+        gpu.step(1);
+        assert!(gpu.lcd_mode == Mode::VRAMAccess);
+        gpu.step(172);
+        gpu.step(200);
+        assert_eq!(gpu.lcd_y_coordinate, 1);
+
+        //Nothing is drawn until the next frame
+        assert_eq!(screen_pixel(&gpu, 8, 0), Color::White as u8);
+        let mut cycles = 0;
+        while cycles < ONE_FRAME_IN_CYCLES {
+            gpu.step(4);
+            cycles += 4;
+        }
+        assert_eq!(screen_pixel(&gpu, 8, 0), Color::Black as u8);
+    }
+}
@@ -1,11 +1,17 @@
+use crate::state::{StateError, StateReader, StateWriter};
+
 #[derive(Debug)]
 pub struct Joypad {
-/* use:
+/* P1, a button group is selected by writing 0 to its bit:
  * - bit 5 for button data
  * - bit 4 for dpad data
- * 3-0 bits are either dpad data or button data
+ * 3-0 bits are the input lines, 0 when pressed. With both groups selected the lines
+ * are ANDed, with neither selected they read 1.
+ * A line going from 1 to 0 requests the joypad interrupt and ends STOP.
  */
-    pub column: bool,
+    select_buttons: bool,
+    select_dpad: bool,
+    lines: u8, //input lines when the interrupt was last checked
 
     up: bool,
     down: bool,
@@ -20,7 +26,9 @@ pub struct Joypad {
 impl Joypad {
     pub fn new() -> Joypad {
         Joypad {
-            column: false,
+            select_buttons: false,
+            select_dpad: false,
+            lines: 0x0F,
 
             up: false,
             down: false,
@@ -34,27 +42,48 @@ impl Joypad {
     }
 
     pub fn poll(&self) -> u8 {
-        let result = if self.column {
-            let button_bit = (1 as u8) << 5;
+        0b1100_0000 | self.selection() | self.input_lines()
+    }
+
+    pub fn write(&mut self, byte: u8) {
+        self.select_buttons = (byte & 0x20) == 0;
+        self.select_dpad = (byte & 0x10) == 0;
+    }
+
+    fn selection(&self) -> u8 {
+        (!self.select_buttons as u8) << 5 | (!self.select_dpad as u8) << 4
+    }
+
+    fn input_lines(&self) -> u8 {
+        let mut lines = 0x0F;
+        if self.select_buttons {
             let start_bit = (!self.start as u8) << 3;
             let select_bit = (!self.select as u8) << 2;
             let b_bit = (!self.b as u8) << 1;
             let a_bit = !self.a as u8;
-
-            let result = button_bit | start_bit | select_bit | b_bit | a_bit;
-            result
+            lines &= start_bit | select_bit | b_bit | a_bit;
         }
-        else {
-            let dpad_bit = 1 << 4;
+        if self.select_dpad {
             let down_bit = (!self.down as u8) << 3;
             let up_bit = (!self.up as u8) << 2;
             let left_bit = (!self.left as u8) << 1;
             let right_bit = !self.right as u8;
+            lines &= down_bit | up_bit | left_bit | right_bit;
+        }
+        lines
+    }
 
-            dpad_bit | down_bit | up_bit | left_bit | right_bit
-        };
+    /* True when a selected line went low since the last check */
+    pub fn check_interrupt(&mut self) -> bool {
+        let lines = self.input_lines();
+        let falling = (self.lines & !lines) != 0;
+        self.lines = lines;
+        falling
+    }
 
-        result
+    /* STOP ends as soon as a selected line is low */
+    pub fn pressed(&self) -> bool {
+        self.input_lines() != 0x0F
     }
 
     pub fn reset(&mut self) {
@@ -78,4 +107,56 @@ impl Joypad {
 
     pub fn select(&mut self) { self.select = true; }
     pub fn start(&mut self) { self.start = true; }
+
+    /* Only the register side is saved, the buttons come from the frontend */
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.selection());
+        state.write_u8(self.lines);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.write(state.read_u8()?);
+        self.lines = state.read_u8()? & 0x0F;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selected_groups_are_anded() {
+        let mut joypad = Joypad::new();
+        joypad.a();
+        joypad.down();
+        joypad.write(0x30);
+        assert_eq!(joypad.poll(), 0xFF);
+        joypad.write(0x10);
+        assert_eq!(joypad.poll(), 0xDE);
+        joypad.write(0x20);
+        assert_eq!(joypad.poll(), 0xE7);
+        joypad.write(0x00);
+        assert_eq!(joypad.poll(), 0xC6);
+    }
+
+    #[test]
+    fn interrupt_on_high_to_low() {
+        let mut joypad = Joypad::new();
+        joypad.write(0x20);
+        assert!(!joypad.check_interrupt());
+        joypad.a();
+        assert!(!joypad.check_interrupt());
+        joypad.left();
+        assert!(joypad.check_interrupt());
+        assert!(!joypad.check_interrupt());
+
+        //Selecting the group of a held button pulls its line low too
+        joypad.write(0x10);
+        assert!(joypad.check_interrupt());
+        joypad.write(0x00);
+        assert!(joypad.check_interrupt());
+        joypad.write(0x10);
+        assert!(!joypad.check_interrupt());
+    }
 }
@@ -1,7 +1,15 @@
+pub mod apu;
+pub mod cartridge;
 pub mod cpu;
+mod dma;
+pub mod error;
 pub mod gpu;
 pub mod joypad;
 mod memory_bus;
 mod interrupts;
+mod mbc;
+pub mod rewind;
+pub mod state;
+pub mod wav;
 
 pub mod register_output;
@@ -1,33 +1,64 @@
+extern crate clap;
 extern crate minifb;
 extern crate rusttype;
 
-use std::fs::File;
-use std::io::Read;
+use std::fs::{self, File};
+use std::io::{BufWriter, ErrorKind, Read};
+use std::path::Path;
 use std::time::{Instant, Duration};
-use std::env;
 use std::thread::sleep;
 
+use clap::{App, Arg};
+
+use erki_boy::cartridge::Cartridge;
 use erki_boy::cpu::CPU;
-use erki_boy::gpu::{ONE_FRAME_IN_CYCLES, SCREEN_WIDTH, SCREEN_HEIGHT, SCREEN_PIXEL_COUNT};
+use erki_boy::gpu::{GPU, Renderer, ONE_FRAME_IN_CYCLES, SCREEN_WIDTH, SCREEN_HEIGHT, SCREEN_PIXEL_COUNT};
 use erki_boy::register_output::{RegisterOutput};
+use erki_boy::rewind::RewindBuffer;
+use erki_boy::wav::WavWriter;
 
 use minifb::{Key, KeyRepeat, Window, WindowOptions};
 
 
 const ONE_SECOND_IN_MICROS: usize = 1000000000;
 const ONE_SECOND_IN_CYCLES: usize = 4190000;
+const SAVE_STATE_SLOTS: [Key; 4] = [Key::F1, Key::F2, Key::F3, Key::F4];
+
+//Snapshot every 4th frame and keep at most 64MB of history, roughly a minute
+const REWIND_INTERVAL_FRAMES: u32 = 4;
+const REWIND_BUFFER_BYTES: usize = 64 * 1024 * 1024;
+const REWIND_FRAME_TIME: Duration = Duration::from_millis(16);
 
 
 fn main() {
     let boot_rom_path = "./dmg_boot.bin";
-    let args: Vec<String> = env::args().collect();
+    let matches = App::new("Erki Boy")
+        .arg(Arg::with_name("ROM")
+             .help("Game ROM to run")
+             .index(1))
+        .arg(Arg::with_name("wav")
+             .long("wav")
+             .value_name("FILE")
+             .help("Records the audio output to a 16-bit PCM WAV file")
+             .takes_value(true))
+        .arg(Arg::with_name("frames")
+             .long("frames")
+             .value_name("COUNT")
+             .help("Runs COUNT frames without opening a window, then exits")
+             .takes_value(true))
+        .arg(Arg::with_name("pixel-fifo")
+             .long("pixel-fifo")
+             .help("Renders with the pixel FIFO, slower but handles mid-line effects"))
+        .arg(Arg::with_name("strict-vram-access")
+             .long("strict-vram-access")
+             .help("Blocks CPU access to VRAM and OAM while the PPU is using them"))
+        .get_matches();
 
-    let game_rom_path = if args.len() == 2 {
-        &args[1]
-    }
-    else {
-        "./ROMS/cpu_instrs.gb"
-    };
+    let game_rom_path = matches.value_of("ROM").unwrap_or("./ROMS/cpu_instrs.gb");
+    let headless_frames = matches.value_of("frames").map(|frames| {
+        frames.parse::<usize>()
+            .unwrap_or_else(|_| panic!("Invalid frame count: {}", frames))
+    });
 
     let mut boot_rom_file = File::open(boot_rom_path).expect("Missing boot ROM");
     let mut boot_rom = Vec::new();
@@ -41,7 +72,50 @@ fn main() {
         .read_to_end(&mut game_rom)
         .expect("error reading game ROM");
 
-    let mut dmg_cpu = CPU::new(Some(boot_rom), game_rom);
+    let cartridge = Cartridge::new(game_rom)
+        .unwrap_or_else(|error| panic!("Error loading game ROM: {}", error));
+    println!("Loaded \"{}\" ({:?})", cartridge.title, cartridge.cartridge_type.mapper);
+    if !cartridge.header_checksum_valid() {
+        println!("Warning: header checksum mismatch");
+    }
+
+    let mut dmg_cpu = CPU::new(Some(boot_rom), cartridge);
+    let renderer = if matches.is_present("pixel-fifo") {
+        Renderer::PixelFifo
+    } else {
+        Renderer::Scanline
+    };
+    dmg_cpu.bus.gpu = GPU::with_options(renderer, matches.is_present("strict-vram-access"));
+
+    let save_path = Path::new(game_rom_path).with_extension("sav");
+    if dmg_cpu.bus.battery_backed() {
+        match dmg_cpu.bus.load_save_file(&save_path) {
+            Ok(()) => println!("Loaded save from {}", save_path.display()),
+            Err(ref error) if error.kind() == ErrorKind::NotFound => {},
+            Err(error) => println!("Error loading save {}: {}", save_path.display(), error),
+        }
+    }
+
+    let mut wav = matches.value_of("wav").map(|path| {
+        WavWriter::create(Path::new(path), dmg_cpu.bus.apu.sample_rate())
+            .unwrap_or_else(|error| panic!("Error creating {}: {}", path, error))
+    });
+
+    if let Some(frames) = headless_frames {
+        for _ in 0..frames {
+            let mut cycles_this_frame = 0;
+            while cycles_this_frame < ONE_FRAME_IN_CYCLES {
+                cycles_this_frame += step(&mut dmg_cpu);
+            }
+            record_audio(&mut wav, &mut dmg_cpu);
+            report_unhandled_io(&mut dmg_cpu);
+        }
+        finish_audio(wav);
+        if dmg_cpu.bus.battery_backed() {
+            write_save(&dmg_cpu, &save_path);
+        }
+        return;
+    }
 
     let mut window = Window::new(
         "Erki Boy",
@@ -56,9 +130,24 @@ fn main() {
     let mut halt_execution = false;
     let mut step_execution = false;
     let mut run_to_next_frame = false;
+    let mut rumbling = false;
     let register_output = RegisterOutput::new();
+    let mut rewind = RewindBuffer::new(REWIND_INTERVAL_FRAMES, REWIND_BUFFER_BYTES);
 
     while window.is_open() && !window.is_key_down(Key::Escape) {
+        //Holding backspace steps back through the rewind history instead of running
+        if window.is_key_down(Key::Backspace) {
+            match rewind.rewind(&mut dmg_cpu) {
+                Ok(_) => {},
+                Err(error) => println!("Error rewinding: {}", error),
+            }
+            draw_frame(&mut window, &mut buffer, &register_output, &dmg_cpu);
+            sleep(REWIND_FRAME_TIME);
+            cycles_this_frame = 0;
+            now = Instant::now();
+            continue;
+        }
+
         let time_delta = now.elapsed().subsec_nanos();
         now = Instant::now();
         let delta = time_delta as f64 / ONE_SECOND_IN_MICROS as f64;
@@ -70,33 +159,30 @@ fn main() {
 
             if !halt_execution || run_to_next_frame {
                 while cycles_elapsed <= cycles_to_run as usize {
-                    cycles_elapsed += dmg_cpu.step() as usize;
+                    cycles_elapsed += step(&mut dmg_cpu);
                 }
             }
             else {
                 if step_execution {
-                    cycles_elapsed += dmg_cpu.step() as usize;
+                    cycles_elapsed += step(&mut dmg_cpu);
                     dmg_cpu.debug_output();
                     step_execution = false;
                 }
             }
             cycles_this_frame += cycles_elapsed;
             if cycles_this_frame >= ONE_FRAME_IN_CYCLES {
-                let text = generate_register_output(
-                    &register_output, &dmg_cpu);
-                for (i, pixel) in dmg_cpu.bus.gpu.screen_buffer.chunks(4).enumerate() {
-                    buffer[i] =
-                        (pixel[3] as u32) << 24 |
-                        (pixel[2] as u32) << 16 |
-                        (pixel[1] as u32) << 8 |
-                        (pixel[0] as u32);
+                draw_frame(&mut window, &mut buffer, &register_output, &dmg_cpu);
+                cycles_this_frame = 0;
+                rewind.frame(&dmg_cpu);
+                record_audio(&mut wav, &mut dmg_cpu);
+                report_unhandled_io(&mut dmg_cpu);
+                if dmg_cpu.bus.battery_backed() && dmg_cpu.bus.save_requested() {
+                    write_save(&dmg_cpu, &save_path);
                 }
-
-                for (i, val) in text.iter().enumerate() {
-                    buffer[i + SCREEN_PIXEL_COUNT] = *val;
+                if dmg_cpu.bus.rumble() != rumbling {
+                    rumbling = !rumbling;
+                    println!("Rumble {}", if rumbling { "on" } else { "off" });
                 }
-                window.update_with_buffer(&buffer).unwrap();
-                cycles_this_frame = 0;
                 if run_to_next_frame {
                     dmg_cpu.debug_output();
                 }
@@ -126,6 +212,21 @@ fn main() {
             }
         });
 
+        //F1-F4 loads a save state slot, holding shift saves to it instead
+        let shift_down = window.is_key_down(Key::LeftShift);
+        window.get_keys_pressed(KeyRepeat::No).map(|keys| {
+            for k in keys {
+                if let Some(slot) = SAVE_STATE_SLOTS.iter().position(|slot_key| *slot_key == k) {
+                    let path = Path::new(game_rom_path).with_extension(format!("ss{}", slot + 1));
+                    if shift_down {
+                        save_state(&dmg_cpu, &path);
+                    } else {
+                        load_state(&mut dmg_cpu, &path);
+                    }
+                }
+            }
+        });
+
         window.get_keys_pressed(KeyRepeat::Yes).map(|keys| {
             for k in keys {
                 match k {
@@ -150,6 +251,82 @@ fn main() {
             }
         });
     }
+
+    finish_audio(wav);
+    if dmg_cpu.bus.battery_backed() {
+        write_save(&dmg_cpu, &save_path);
+    }
+}
+
+/* The game keeps running after a bad opcode, the CPU is just locked up */
+fn step(cpu: &mut CPU) -> usize {
+    match cpu.try_step() {
+        Ok(cycles) => cycles as usize,
+        Err(error) => {
+            println!("{}", error);
+            4
+        }
+    }
+}
+
+fn report_unhandled_io(cpu: &mut CPU) {
+    for address in cpu.bus.take_unhandled_io() {
+        println!("Warning: unhandled IO register 0x{:04X}", address);
+    }
+}
+
+fn record_audio(wav: &mut Option<WavWriter<BufWriter<File>>>, cpu: &mut CPU) {
+    let samples = cpu.bus.apu.take_samples();
+    if let Some(wav) = wav {
+        if let Err(error) = wav.write_samples(&samples) {
+            println!("Error writing audio: {}", error);
+        }
+    }
+}
+
+fn finish_audio(wav: Option<WavWriter<BufWriter<File>>>) {
+    if let Some(Err(error)) = wav.map(|wav| wav.finish()) {
+        println!("Error writing audio: {}", error);
+    }
+}
+
+fn write_save(cpu: &CPU, path: &Path) {
+    if let Err(error) = cpu.bus.write_save_file(path) {
+        println!("Error writing save {}: {}", path.display(), error);
+    }
+}
+
+fn save_state(cpu: &CPU, path: &Path) {
+    match fs::write(path, cpu.save_state()) {
+        Ok(()) => println!("Saved state to {}", path.display()),
+        Err(error) => println!("Error writing state {}: {}", path.display(), error),
+    }
+}
+
+fn load_state(cpu: &mut CPU, path: &Path) {
+    let result = fs::read(path)
+        .map_err(|error| error.to_string())
+        .and_then(|state| cpu.load_state(&state).map_err(|error| error.to_string()));
+    match result {
+        Ok(()) => println!("Loaded state from {}", path.display()),
+        Err(error) => println!("Error loading state {}: {}", path.display(), error),
+    }
+}
+
+fn draw_frame(window: &mut Window, buffer: &mut [u32], register_output: &RegisterOutput, cpu: &CPU) {
+    let text = generate_register_output(register_output, cpu);
+    for (i, pixel) in cpu.bus.gpu.screen_buffer.chunks(4).enumerate() {
+        buffer[i] =
+            (pixel[3] as u32) << 24 |
+            (pixel[2] as u32) << 16 |
+            (pixel[1] as u32) << 8 |
+            (pixel[0] as u32);
+    }
+
+    for (i, val) in text.iter().enumerate() {
+        buffer[i + SCREEN_PIXEL_COUNT] = *val;
+    }
+    window.update_with_buffer(buffer).unwrap();
 }
 
 fn generate_register_output(ro: &RegisterOutput, cpu: &CPU) -> Vec<u32> {
@@ -0,0 +1,234 @@
+use super::{MemoryBankController, ROM_BANK_SIZE, RAM_BANK_SIZE};
+use crate::state::{StateError, StateReader, StateWriter};
+
+#[derive(Debug, PartialEq)]
+pub enum BankingMode {
+    Rom,
+    Ram,
+}
+
+pub struct MBC1 {
+    /* Registers:
+     * 0000-1FFF RAM enable, 0x0A in the lower nibble enables
+     * 2000-3FFF lower 5 bits of ROM bank number, 0 is treated as 1
+     * 4000-5FFF 2 bit RAM bank number or upper bits of ROM bank number
+     * 6000-7FFF banking mode select
+     */
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    ram_dirty: bool,
+    save_requested: bool,
+    rom_bank: u8,
+    upper_bank: u8,
+    mode: BankingMode,
+}
+
+impl MBC1 {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> MBC1 {
+        MBC1 {
+            rom,
+            ram: vec![0xFF; ram_size],
+            ram_enabled: false,
+            ram_dirty: false,
+            save_requested: false,
+            rom_bank: 1,
+            upper_bank: 0,
+            mode: BankingMode::Rom,
+        }
+    }
+
+    fn rom_bank_count(&self) -> usize {
+        std::cmp::max(self.rom.len() / ROM_BANK_SIZE, 2)
+    }
+
+    fn ram_bank_count(&self) -> usize {
+        std::cmp::max(self.ram.len() / RAM_BANK_SIZE, 1)
+    }
+
+    fn low_rom_bank(&self) -> usize {
+        match self.mode {
+            BankingMode::Rom => 0,
+            BankingMode::Ram => ((self.upper_bank as usize) << 5) % self.rom_bank_count(),
+        }
+    }
+
+    fn high_rom_bank(&self) -> usize {
+        let bank = (self.upper_bank as usize) << 5 | self.rom_bank as usize;
+        bank % self.rom_bank_count()
+    }
+
+    fn ram_bank(&self) -> usize {
+        match self.mode {
+            BankingMode::Rom => 0,
+            BankingMode::Ram => self.upper_bank as usize % self.ram_bank_count(),
+        }
+    }
+
+    fn ram_offset(&self, address: u16) -> usize {
+        self.ram_bank() * RAM_BANK_SIZE + address as usize
+    }
+}
+
+impl MemoryBankController for MBC1 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let address = address as usize;
+        let bank = if address < ROM_BANK_SIZE {
+            self.low_rom_bank()
+        } else {
+            self.high_rom_bank()
+        };
+        let offset = bank * ROM_BANK_SIZE + (address % ROM_BANK_SIZE);
+        *self.rom.get(offset).unwrap_or(&0xFF)
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                let enabled = (value & 0x0F) == 0x0A;
+                if self.ram_enabled && !enabled && self.ram_dirty {
+                    self.save_requested = true;
+                    self.ram_dirty = false;
+                }
+                self.ram_enabled = enabled;
+            }
+            0x2000..=0x3FFF => {
+                let bank = value & 0x1F;
+                self.rom_bank = if bank == 0 { 1 } else { bank };
+            }
+            0x4000..=0x5FFF => self.upper_bank = value & 0b11,
+            _ => {
+                self.mode = if (value & 0b1) == 1 {
+                    BankingMode::Ram
+                } else {
+                    BankingMode::Rom
+                };
+            }
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        *self.ram.get(self.ram_offset(address)).unwrap_or(&0xFF)
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        let offset = self.ram_offset(address);
+        if let Some(byte) = self.ram.get_mut(offset) {
+            *byte = value;
+            self.ram_dirty = true;
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn take_save_request(&mut self) -> bool {
+        let requested = self.save_requested;
+        self.save_requested = false;
+        requested
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.ram_enabled);
+        state.write_bool(self.ram_dirty);
+        state.write_u8(self.rom_bank);
+        state.write_u8(self.upper_bank);
+        state.write_bool(self.mode == BankingMode::Ram);
+        state.write_sized_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enabled = state.read_bool()?;
+        self.ram_dirty = state.read_bool()?;
+        self.rom_bank = state.read_u8()?;
+        self.upper_bank = state.read_u8()?;
+        self.mode = if state.read_bool()? {
+            BankingMode::Ram
+        } else {
+            BankingMode::Rom
+        };
+        state.read_sized_bytes(&mut self.ram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn banked_rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+        }
+        rom
+    }
+
+    #[test]
+    fn bank_zero_selects_bank_one() {
+        let mut mbc = MBC1::new(banked_rom(4), 0);
+        mbc.write_rom(0x2000, 0);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_rom(0x2000, 3);
+        assert_eq!(mbc.read_rom(0x4000), 3);
+        assert_eq!(mbc.read_rom(0x0000), 0);
+    }
+
+    #[test]
+    fn upper_bits_select_high_banks() {
+        let mut mbc = MBC1::new(banked_rom(128), 0);
+        mbc.write_rom(0x2000, 0x02);
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.read_rom(0x4000), 0x22);
+        assert_eq!(mbc.read_rom(0x0000), 0);
+
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(0x0000), 0x20);
+    }
+
+    #[test]
+    fn rom_bank_wraps_to_rom_size() {
+        let mut mbc = MBC1::new(banked_rom(4), 0);
+        mbc.write_rom(0x2000, 0x05);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+    }
+
+    #[test]
+    fn ram_needs_to_be_enabled() {
+        let mut mbc = MBC1::new(banked_rom(2), RAM_BANK_SIZE);
+        mbc.write_ram(0x10, 0x42);
+        assert_eq!(mbc.read_ram(0x10), 0xFF);
+
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0x10, 0x42);
+        assert_eq!(mbc.read_ram(0x10), 0x42);
+
+        mbc.write_rom(0x0000, 0x00);
+        assert_eq!(mbc.read_ram(0x10), 0xFF);
+    }
+
+    #[test]
+    fn ram_banks_switch_in_ram_mode() {
+        let mut mbc = MBC1::new(banked_rom(2), RAM_BANK_SIZE * 4);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0x00, 0x11);
+        mbc.write_rom(0x6000, 0x01);
+        mbc.write_rom(0x4000, 0x02);
+        assert_eq!(mbc.read_ram(0x00), 0xFF);
+        mbc.write_ram(0x00, 0x22);
+
+        mbc.write_rom(0x6000, 0x00);
+        assert_eq!(mbc.read_ram(0x00), 0x11);
+        assert_eq!(mbc.ram[2 * RAM_BANK_SIZE], 0x22);
+    }
+}
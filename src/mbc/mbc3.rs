@@ -0,0 +1,569 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{MemoryBankController, ROM_BANK_SIZE, RAM_BANK_SIZE};
+use crate::state::{StateError, StateReader, StateWriter};
+
+const CYCLES_PER_SECOND: u32 = 4194304;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+const DAY_COUNTER_LIMIT: u64 = 512;
+
+/* RTC footer appended to .sav files, the format used by VBA-M and BGB:
+ * 5 x u32 little endian current seconds, minutes, hours, day low, day high
+ * 5 x u32 little endian latched registers in the same order
+ * u64 little endian unix timestamp of when the file was written
+ * Some emulators write a 32 bit timestamp, giving a 44 byte footer
+ */
+const RTC_FOOTER_SIZE: usize = 48;
+const RTC_FOOTER_SHORT_SIZE: usize = 44;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RtcRegisters {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    pub days: u16,
+    pub halted: bool,
+    pub day_carry: bool,
+}
+
+impl RtcRegisters {
+    fn tick(&mut self) {
+        //Counters are 6/6/5 bits wide and can be written with out of range
+        //values, in which case they count up to the bit limit and wrap
+        //without carrying into the next counter
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days as u64 == DAY_COUNTER_LIMIT {
+            self.days = 0;
+            self.day_carry = true;
+        }
+    }
+
+    fn in_range(&self) -> bool {
+        self.seconds < 60 && self.minutes < 60 && self.hours < 24
+    }
+
+    pub fn advance(&mut self, mut seconds: u64) {
+        if self.halted {
+            return;
+        }
+        while seconds > 0 && !self.in_range() {
+            self.tick();
+            seconds -= 1;
+        }
+        if seconds == 0 {
+            return;
+        }
+
+        let total = self.days as u64 * SECONDS_PER_DAY +
+            self.hours as u64 * 3600 +
+            self.minutes as u64 * 60 +
+            self.seconds as u64 +
+            seconds;
+
+        let days = total / SECONDS_PER_DAY;
+        if days >= DAY_COUNTER_LIMIT {
+            self.day_carry = true;
+        }
+        self.days = (days % DAY_COUNTER_LIMIT) as u16;
+        self.hours = ((total % SECONDS_PER_DAY) / 3600) as u8;
+        self.minutes = ((total % 3600) / 60) as u8;
+        self.seconds = (total % 60) as u8;
+    }
+
+    fn read(&self, register: u8) -> u8 {
+        match register {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => (self.days & 0xFF) as u8,
+            _ => {
+                0b0011_1110 |
+                (self.day_carry as u8) << 7 |
+                (self.halted as u8) << 6 |
+                ((self.days >> 8) & 0b1) as u8
+            }
+        }
+    }
+
+    fn footer(&self) -> [u32; 5] {
+        [
+            self.seconds as u32,
+            self.minutes as u32,
+            self.hours as u32,
+            (self.days & 0xFF) as u32,
+            (self.read(0x0C) & 0b1100_0001) as u32,
+        ]
+    }
+
+    fn from_footer(words: &[u32]) -> RtcRegisters {
+        let mut registers = RtcRegisters::default();
+        for (register, word) in (0x08..=0x0C).zip(words.iter()) {
+            registers.write(register, *word as u8);
+        }
+        registers
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        for register in 0x08..=0x0C {
+            state.write_u8(self.read(register));
+        }
+    }
+
+    fn load_state(state: &mut StateReader) -> Result<RtcRegisters, StateError> {
+        let mut registers = RtcRegisters::default();
+        for register in 0x08..=0x0C {
+            registers.write(register, state.read_u8()?);
+        }
+        Ok(registers)
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        match register {
+            0x08 => self.seconds = value & 0x3F,
+            0x09 => self.minutes = value & 0x3F,
+            0x0A => self.hours = value & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | value as u16,
+            _ => {
+                self.days = (self.days & 0xFF) | ((value as u16 & 0b1) << 8);
+                self.halted = (value & 0x40) != 0;
+                self.day_carry = (value & 0x80) != 0;
+            }
+        }
+    }
+}
+
+pub struct MBC3 {
+    /* Registers:
+     * 0000-1FFF RAM and RTC enable, 0x0A in the lower nibble enables
+     * 2000-3FFF 7 bit ROM bank number, 0 is treated as 1
+     * 4000-5FFF 0x00-0x03 selects RAM bank, 0x08-0x0C selects RTC register
+     * 6000-7FFF writing 0x00 then 0x01 latches the clock registers
+     *
+     * RTC registers:
+     * 08 seconds 0-59
+     * 09 minutes 0-59
+     * 0A hours 0-23
+     * 0B lower 8 bits of day counter
+     * 0C bit 0 day counter msb, bit 6 halt, bit 7 day counter carry
+     */
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    ram_dirty: bool,
+    save_requested: bool,
+    rom_bank: u8,
+    ram_select: u8,
+    latch_armed: bool,
+
+    has_timer: bool,
+    rtc: RtcRegisters,
+    latched_rtc: RtcRegisters,
+    rtc_cycles: u32,
+    //When set the clock follows the host wall-clock instead of emulated time
+    host_sync: Option<SystemTime>,
+}
+
+impl MBC3 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_timer: bool) -> MBC3 {
+        MBC3 {
+            rom,
+            ram: vec![0xFF; ram_size],
+            ram_enabled: false,
+            ram_dirty: false,
+            save_requested: false,
+            rom_bank: 1,
+            ram_select: 0,
+            latch_armed: false,
+
+            has_timer,
+            rtc: Default::default(),
+            latched_rtc: Default::default(),
+            rtc_cycles: 0,
+            host_sync: None,
+        }
+    }
+
+    fn rom_bank_count(&self) -> usize {
+        std::cmp::max(self.rom.len() / ROM_BANK_SIZE, 2)
+    }
+
+    fn ram_offset(&self, address: u16) -> Option<usize> {
+        let bank_count = self.ram.len() / RAM_BANK_SIZE;
+        if bank_count == 0 {
+            return if (address as usize) < self.ram.len() {
+                Some(address as usize)
+            } else {
+                None
+            };
+        }
+        Some((self.ram_select as usize % bank_count) * RAM_BANK_SIZE + address as usize)
+    }
+
+    fn sync_with_host(&mut self) {
+        if let Some(last_sync) = self.host_sync {
+            if let Ok(elapsed) = last_sync.elapsed() {
+                let seconds = elapsed.as_secs();
+                self.rtc.advance(seconds);
+                self.host_sync = Some(last_sync + Duration::from_secs(seconds));
+            }
+        }
+    }
+}
+
+impl MemoryBankController for MBC3 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let address = address as usize;
+        let bank = if address < ROM_BANK_SIZE {
+            0
+        } else {
+            self.rom_bank as usize % self.rom_bank_count()
+        };
+        let offset = bank * ROM_BANK_SIZE + (address % ROM_BANK_SIZE);
+        *self.rom.get(offset).unwrap_or(&0xFF)
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                let enabled = (value & 0x0F) == 0x0A;
+                if self.ram_enabled && !enabled && self.ram_dirty {
+                    self.save_requested = true;
+                    self.ram_dirty = false;
+                }
+                self.ram_enabled = enabled;
+            }
+            0x2000..=0x3FFF => {
+                let bank = value & 0x7F;
+                self.rom_bank = if bank == 0 { 1 } else { bank };
+            }
+            0x4000..=0x5FFF => self.ram_select = value,
+            _ => {
+                if value == 0x01 && self.latch_armed {
+                    self.sync_with_host();
+                    self.latched_rtc = self.rtc;
+                }
+                self.latch_armed = value == 0x00;
+            }
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        match self.ram_select {
+            0x00..=0x03 => {
+                self.ram_offset(address)
+                    .and_then(|offset| self.ram.get(offset))
+                    .cloned()
+                    .unwrap_or(0xFF)
+            }
+            0x08..=0x0C => self.latched_rtc.read(self.ram_select),
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        match self.ram_select {
+            0x00..=0x03 => {
+                if let Some(offset) = self.ram_offset(address) {
+                    if let Some(byte) = self.ram.get_mut(offset) {
+                        *byte = value;
+                        self.ram_dirty = true;
+                    }
+                }
+            }
+            0x08..=0x0C => {
+                self.sync_with_host();
+                self.ram_dirty = true;
+                if self.ram_select == 0x08 {
+                    self.rtc_cycles = 0;
+                }
+                self.rtc.write(self.ram_select, value);
+                self.latched_rtc.write(self.ram_select, value);
+            }
+            _ => {}
+        }
+    }
+
+    fn step(&mut self, cycles: u16) {
+        if self.host_sync.is_some() || self.rtc.halted {
+            return;
+        }
+        self.rtc_cycles += cycles as u32;
+        while self.rtc_cycles >= CYCLES_PER_SECOND {
+            self.rtc_cycles -= CYCLES_PER_SECOND;
+            self.rtc.tick();
+        }
+    }
+
+    fn sync_rtc_to_host(&mut self, sync: bool) {
+        self.host_sync = if sync { Some(SystemTime::now()) } else { None };
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn take_save_request(&mut self) -> bool {
+        let requested = self.save_requested;
+        self.save_requested = false;
+        requested
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if !self.has_timer {
+            return data;
+        }
+
+        for word in self.rtc.footer().iter().chain(self.latched_rtc.footer().iter()) {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        data.extend_from_slice(&timestamp.to_le_bytes());
+        data
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let length = std::cmp::min(self.ram.len(), data.len());
+        self.ram[..length].copy_from_slice(&data[..length]);
+
+        let footer = &data[length..];
+        if !self.has_timer ||
+            (footer.len() != RTC_FOOTER_SIZE && footer.len() != RTC_FOOTER_SHORT_SIZE) {
+            return;
+        }
+
+        let mut words = [0u32; 10];
+        for (i, word) in words.iter_mut().enumerate() {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&footer[i * 4..i * 4 + 4]);
+            *word = u32::from_le_bytes(bytes);
+        }
+        let timestamp = if footer.len() == RTC_FOOTER_SIZE {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&footer[40..48]);
+            u64::from_le_bytes(bytes)
+        } else {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&footer[40..44]);
+            u32::from_le_bytes(bytes) as u64
+        };
+
+        self.rtc = RtcRegisters::from_footer(&words[0..5]);
+        self.latched_rtc = RtcRegisters::from_footer(&words[5..10]);
+
+        //The battery kept the clock running while the game was not
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        if now > timestamp {
+            self.rtc.advance(now - timestamp);
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.ram_enabled);
+        state.write_bool(self.ram_dirty);
+        state.write_u8(self.rom_bank);
+        state.write_u8(self.ram_select);
+        state.write_bool(self.latch_armed);
+        self.rtc.save_state(state);
+        self.latched_rtc.save_state(state);
+        state.write_u32(self.rtc_cycles);
+        state.write_sized_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enabled = state.read_bool()?;
+        self.ram_dirty = state.read_bool()?;
+        self.rom_bank = state.read_u8()?;
+        self.ram_select = state.read_u8()?;
+        self.latch_armed = state.read_bool()?;
+        self.rtc = RtcRegisters::load_state(state)?;
+        self.latched_rtc = RtcRegisters::load_state(state)?;
+        self.rtc_cycles = state.read_u32()?;
+        //A host synced clock continues from the restored time
+        if self.host_sync.is_some() {
+            self.host_sync = Some(SystemTime::now());
+        }
+        state.read_sized_bytes(&mut self.ram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mbc3_with_rtc_enabled() -> MBC3 {
+        let mut mbc = MBC3::new(vec![0; ROM_BANK_SIZE * 4], RAM_BANK_SIZE * 4, true);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc
+    }
+
+    fn latch(mbc: &mut MBC3) {
+        mbc.write_rom(0x6000, 0x00);
+        mbc.write_rom(0x6000, 0x01);
+    }
+
+    fn read_rtc(mbc: &mut MBC3, register: u8) -> u8 {
+        mbc.write_rom(0x4000, register);
+        mbc.read_ram(0)
+    }
+
+    #[test]
+    fn rom_bank_uses_seven_bits() {
+        let mut rom = vec![0; ROM_BANK_SIZE * 128];
+        rom[0x7F * ROM_BANK_SIZE] = 0x7F;
+        let mut mbc = MBC3::new(rom, 0, false);
+        mbc.write_rom(0x2000, 0xFF);
+        assert_eq!(mbc.read_rom(0x4000), 0x7F);
+    }
+
+    #[test]
+    fn ram_banks_are_separate() {
+        let mut mbc = mbc3_with_rtc_enabled();
+        mbc.write_rom(0x4000, 0x00);
+        mbc.write_ram(0x0000, 0x12);
+        mbc.write_rom(0x4000, 0x03);
+        mbc.write_ram(0x0000, 0x34);
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(mbc.read_ram(0x0000), 0x12);
+    }
+
+    #[test]
+    fn clock_follows_emulated_time() {
+        let mut mbc = mbc3_with_rtc_enabled();
+        for _ in 0..(CYCLES_PER_SECOND / 0x8000) * 61 {
+            mbc.step(0x8000);
+        }
+        assert_eq!(read_rtc(&mut mbc, 0x08), 0);
+
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 1);
+        assert_eq!(read_rtc(&mut mbc, 0x09), 1);
+    }
+
+    #[test]
+    fn latch_needs_zero_then_one() {
+        let mut mbc = mbc3_with_rtc_enabled();
+        mbc.rtc.seconds = 10;
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 0);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 10);
+    }
+
+    #[test]
+    fn halted_clock_does_not_tick() {
+        let mut mbc = mbc3_with_rtc_enabled();
+        mbc.write_rom(0x4000, 0x0C);
+        mbc.write_ram(0, 0x40);
+        for _ in 0..(CYCLES_PER_SECOND / 0x8000) * 2 {
+            mbc.step(0x8000);
+        }
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 0);
+        assert_eq!(read_rtc(&mut mbc, 0x0C) & 0x40, 0x40);
+    }
+
+    #[test]
+    fn day_counter_overflow_sets_carry() {
+        let mut rtc = RtcRegisters {
+            seconds: 59,
+            minutes: 59,
+            hours: 23,
+            days: 511,
+            halted: false,
+            day_carry: false,
+        };
+        rtc.tick();
+        assert_eq!(rtc.days, 0);
+        assert_eq!(rtc.day_carry, true);
+    }
+
+    #[test]
+    fn out_of_range_seconds_wrap_without_carry() {
+        let mut rtc = RtcRegisters::default();
+        rtc.seconds = 63;
+        rtc.tick();
+        assert_eq!(rtc.seconds, 0);
+        assert_eq!(rtc.minutes, 0);
+    }
+
+    #[test]
+    fn advance_many_seconds() {
+        let mut rtc = RtcRegisters::default();
+        rtc.advance(SECONDS_PER_DAY * 2 + 3600 + 61);
+        assert_eq!(rtc.days, 2);
+        assert_eq!(rtc.hours, 1);
+        assert_eq!(rtc.minutes, 1);
+        assert_eq!(rtc.seconds, 1);
+    }
+
+    #[test]
+    fn save_data_has_rtc_footer() {
+        let mut mbc = mbc3_with_rtc_enabled();
+        mbc.write_ram(0x0000, 0x42);
+        mbc.write_rom(0x4000, 0x09);
+        mbc.write_ram(0x0000, 12);
+        mbc.write_rom(0x4000, 0x0C);
+        mbc.write_ram(0x0000, 0x41);
+
+        let data = mbc.save_data();
+        assert_eq!(data.len(), RAM_BANK_SIZE * 4 + RTC_FOOTER_SIZE);
+        assert_eq!(data[0], 0x42);
+        let footer = &data[RAM_BANK_SIZE * 4..];
+        assert_eq!(footer[4], 12);
+        assert_eq!(footer[16], 0x41);
+        assert_eq!(footer[24], 12);
+
+        let mut loaded = MBC3::new(vec![0; ROM_BANK_SIZE * 4], RAM_BANK_SIZE * 4, true);
+        loaded.load_save_data(&data);
+        assert_eq!(loaded.ram[0], 0x42);
+        assert_eq!(loaded.rtc.minutes, 12);
+        assert_eq!(loaded.rtc.days, 0x100);
+        assert_eq!(loaded.rtc.halted, true);
+        assert_eq!(loaded.latched_rtc.minutes, 12);
+    }
+
+    #[test]
+    fn disabling_ram_after_write_requests_save() {
+        let mut mbc = mbc3_with_rtc_enabled();
+        mbc.write_rom(0x0000, 0x00);
+        assert_eq!(mbc.take_save_request(), false);
+
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_ram(0x0000, 0x01);
+        mbc.write_rom(0x0000, 0x00);
+        assert_eq!(mbc.take_save_request(), true);
+        assert_eq!(mbc.take_save_request(), false);
+    }
+}
@@ -0,0 +1,193 @@
+use super::{MemoryBankController, ROM_BANK_SIZE, RAM_BANK_SIZE};
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub struct MBC5 {
+    /* Registers:
+     * 0000-1FFF RAM enable, 0x0A enables
+     * 2000-2FFF lower 8 bits of ROM bank number, bank 0 can be selected
+     * 3000-3FFF bit 8 of ROM bank number
+     * 4000-5FFF 4 bit RAM bank number, on rumble carts bit 3 drives the motor
+     */
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    ram_dirty: bool,
+    save_requested: bool,
+    rom_bank: u16,
+    ram_bank: u8,
+    has_rumble: bool,
+    rumble: bool,
+}
+
+impl MBC5 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_rumble: bool) -> MBC5 {
+        MBC5 {
+            rom,
+            ram: vec![0xFF; ram_size],
+            ram_enabled: false,
+            ram_dirty: false,
+            save_requested: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble,
+            rumble: false,
+        }
+    }
+
+    fn rom_bank_count(&self) -> usize {
+        std::cmp::max(self.rom.len() / ROM_BANK_SIZE, 2)
+    }
+
+    fn ram_offset(&self, address: u16) -> usize {
+        let bank_count = std::cmp::max(self.ram.len() / RAM_BANK_SIZE, 1);
+        (self.ram_bank as usize % bank_count) * RAM_BANK_SIZE + address as usize
+    }
+}
+
+impl MemoryBankController for MBC5 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let address = address as usize;
+        let bank = if address < ROM_BANK_SIZE {
+            0
+        } else {
+            self.rom_bank as usize % self.rom_bank_count()
+        };
+        let offset = bank * ROM_BANK_SIZE + (address % ROM_BANK_SIZE);
+        *self.rom.get(offset).unwrap_or(&0xFF)
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                let enabled = value == 0x0A;
+                if self.ram_enabled && !enabled && self.ram_dirty {
+                    self.save_requested = true;
+                    self.ram_dirty = false;
+                }
+                self.ram_enabled = enabled;
+            }
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+            0x3000..=0x3FFF => {
+                self.rom_bank = (self.rom_bank & 0xFF) | ((value as u16 & 0b1) << 8)
+            }
+            0x4000..=0x5FFF => {
+                if self.has_rumble {
+                    self.rumble = (value & 0x08) != 0;
+                    self.ram_bank = value & 0x07;
+                } else {
+                    self.ram_bank = value & 0x0F;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        *self.ram.get(self.ram_offset(address)).unwrap_or(&0xFF)
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        let offset = self.ram_offset(address);
+        if let Some(byte) = self.ram.get_mut(offset) {
+            *byte = value;
+            self.ram_dirty = true;
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn take_save_request(&mut self) -> bool {
+        let requested = self.save_requested;
+        self.save_requested = false;
+        requested
+    }
+
+    fn rumble(&self) -> bool {
+        self.rumble
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.ram_enabled);
+        state.write_bool(self.ram_dirty);
+        state.write_u16(self.rom_bank);
+        state.write_u8(self.ram_bank);
+        state.write_bool(self.rumble);
+        state.write_sized_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enabled = state.read_bool()?;
+        self.ram_dirty = state.read_bool()?;
+        self.rom_bank = state.read_u16()?;
+        self.ram_bank = state.read_u8()?;
+        self.rumble = state.read_bool()?;
+        state.read_sized_bytes(&mut self.ram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn banked_rom(banks: usize) -> Vec<u8> {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE];
+        for bank in 0..banks {
+            rom[bank * ROM_BANK_SIZE] = bank as u8;
+            rom[bank * ROM_BANK_SIZE + 1] = (bank >> 8) as u8;
+        }
+        rom
+    }
+
+    #[test]
+    fn nine_bit_rom_bank() {
+        let mut mbc = MBC5::new(banked_rom(512), 0, false);
+        mbc.write_rom(0x2000, 0x23);
+        mbc.write_rom(0x3000, 0x01);
+        assert_eq!(mbc.read_rom(0x4000), 0x23);
+        assert_eq!(mbc.read_rom(0x4001), 0x01);
+    }
+
+    #[test]
+    fn rom_bank_zero_can_be_selected() {
+        let mut mbc = MBC5::new(banked_rom(4), 0, false);
+        mbc.write_rom(0x2000, 0x02);
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0x00);
+    }
+
+    #[test]
+    fn sixteen_ram_banks() {
+        let mut mbc = MBC5::new(banked_rom(2), RAM_BANK_SIZE * 16, false);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x0F);
+        mbc.write_ram(0x0000, 0x42);
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(mbc.read_ram(0x0000), 0xFF);
+        assert_eq!(mbc.ram[15 * RAM_BANK_SIZE], 0x42);
+    }
+
+    #[test]
+    fn rumble_uses_ram_bank_bit_three() {
+        let mut mbc = MBC5::new(banked_rom(2), RAM_BANK_SIZE * 8, true);
+        mbc.write_rom(0x0000, 0x0A);
+        mbc.write_rom(0x4000, 0x09);
+        assert_eq!(mbc.rumble(), true);
+        mbc.write_ram(0x0000, 0x42);
+        assert_eq!(mbc.ram[RAM_BANK_SIZE], 0x42);
+
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.rumble(), false);
+    }
+}
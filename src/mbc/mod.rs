@@ -0,0 +1,114 @@
+pub mod mbc1;
+pub mod mbc3;
+pub mod mbc5;
+
+use self::mbc1::MBC1;
+use self::mbc3::MBC3;
+use self::mbc5::MBC5;
+use crate::cartridge::{CartridgeType, Mapper};
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+pub trait MemoryBankController {
+    /* Reads from 0x0000-0x7FFF, the controller decides which ROM bank is visible */
+    fn read_rom(&self, address: u16) -> u8;
+    /* Writes to 0x0000-0x7FFF end up in the controller registers */
+    fn write_rom(&mut self, address: u16, value: u8);
+    /* Reads and writes to external RAM at 0xA000-0xBFFF, address is relative to 0xA000 */
+    fn read_ram(&self, address: u16) -> u8;
+    fn write_ram(&mut self, address: u16, value: u8);
+
+    /* Advances controllers that keep time, e.g. the MBC3 real time clock */
+    fn step(&mut self, _cycles: u16) {}
+    fn sync_rtc_to_host(&mut self, _sync: bool) {}
+
+    /* State of the rumble motor on carts that have one */
+    fn rumble(&self) -> bool { false }
+
+    /* External RAM contents, persisted for battery backed cartridges */
+    fn ram(&self) -> &[u8];
+    fn ram_mut(&mut self) -> &mut [u8];
+
+    /* Set when the game disables RAM after writing to it, a good time to flush saves */
+    fn take_save_request(&mut self) -> bool { false }
+
+    /* Raw .sav file contents, RAM followed by any controller specific footer */
+    fn save_data(&self) -> Vec<u8> {
+        self.ram().to_vec()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let ram = self.ram_mut();
+        let length = std::cmp::min(ram.len(), data.len());
+        ram[..length].copy_from_slice(&data[..length]);
+    }
+
+    /* Bank registers and RAM for save states, ROM is reloaded from the cartridge */
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError>;
+}
+
+pub struct RomOnly {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+}
+
+impl RomOnly {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> RomOnly {
+        RomOnly {
+            rom,
+            ram: vec![0xFF; ram_size],
+        }
+    }
+}
+
+impl MemoryBankController for RomOnly {
+    fn read_rom(&self, address: u16) -> u8 {
+        *self.rom.get(address as usize).unwrap_or(&0xFF)
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        //No controller to talk to, writes land in the ROM image which lets
+        //tests poke programs straight into memory
+        if let Some(byte) = self.rom.get_mut(address as usize) {
+            *byte = value;
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        *self.ram.get(address as usize).unwrap_or(&0xFF)
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if let Some(byte) = self.ram.get_mut(address as usize) {
+            *byte = value;
+        }
+    }
+
+    fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_sized_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.read_sized_bytes(&mut self.ram)
+    }
+}
+
+pub fn new(cartridge_type: CartridgeType, rom: Vec<u8>, ram_size: usize) -> Box<dyn MemoryBankController> {
+    match cartridge_type.mapper {
+        Mapper::RomOnly => Box::new(RomOnly::new(rom, ram_size)),
+        Mapper::MBC1 => Box::new(MBC1::new(rom, ram_size)),
+        Mapper::MBC3 => Box::new(MBC3::new(rom, ram_size, cartridge_type.timer)),
+        Mapper::MBC5 => Box::new(MBC5::new(rom, ram_size, cartridge_type.rumble)),
+    }
+}
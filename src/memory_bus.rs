@@ -1,8 +1,16 @@
+use crate::apu::{APU, DEFAULT_SAMPLE_RATE};
+use crate::cartridge::{Cartridge, CartridgeType};
+use crate::dma::OamDma;
 use crate::gpu::{ GPU, Mode, ObjSize, TileData, TileMap };
-use crate::interrupts::{Interrupts};
+use crate::interrupts::{InterruptLocation, Interrupts};
 use crate::joypad::{Joypad};
-use std::fs::{File};
-use std::io::prelude::*;
+use crate::mbc::{self, MemoryBankController};
+use crate::state::{StateError, StateReader, StateWriter};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, prelude::*};
+use std::path::Path;
 
 
 const BOOT_ROM_START: usize = 0x00;
@@ -11,11 +19,9 @@ const BOOT_ROM_SIZE: usize = BOOT_ROM_END - BOOT_ROM_START + 1;
 
 const ROM_BANK_START: usize = 0x0000;
 const ROM_BANK_END: usize = 0x3FFF;
-const ROM_BANK_SIZE: usize = ROM_BANK_END - ROM_BANK_START + 1;
 
 const ROM_SWITCHABLE_BANK_START: usize = 0x4000;
 const ROM_SWITCHABLE_BANK_END: usize = 0x7FFF;
-const ROM_SWITCHABLE_BANK_SIZE: usize = ROM_SWITCHABLE_BANK_END - ROM_SWITCHABLE_BANK_START + 1;
 
 pub const VIDEO_RAM_START: usize = 0x8000;
 const VIDEO_RAM_END: usize = 0x9FFF;
@@ -23,7 +29,6 @@ pub const VIDEO_RAM_SIZE: usize = VIDEO_RAM_END - VIDEO_RAM_START + 1;
 
 const EXTERNAL_RAM_START: usize = 0xA000;
 const EXTERNAL_RAM_END: usize = 0xBFFF;
-const EXTERNAL_RAM_SIZE: usize = EXTERNAL_RAM_END - EXTERNAL_RAM_START + 1;
 
 const WORKING_RAM_START: usize = 0xC000;
 const WORKING_RAM_END: usize = 0xDFFF;
@@ -59,60 +64,166 @@ pub enum TimerFrequency {
 }
 
 impl TimerFrequency {
-    fn in_ticks(&self) -> usize {
+    //Bit of the system counter whose falling edge increments TIMA
+    fn counter_bit(&self) -> u16 {
         match self {
-            TimerFrequency::F4096 => 1024,
-            TimerFrequency::F16384 => 256,
-            TimerFrequency::F65536 => 64,
-            TimerFrequency::F262144 => 16,
+            TimerFrequency::F4096 => 1 << 9,
+            TimerFrequency::F16384 => 1 << 7,
+            TimerFrequency::F65536 => 1 << 5,
+            TimerFrequency::F262144 => 1 << 3,
         }
-
     }
 }
 
+/* DIV and TIMA both run off a 16 bit system counter that goes up every cycle,
+ * DIV is its upper byte. TIMA goes up on the falling edge of the selected counter
+ * bit ANDed with the enable bit, so resetting DIV or changing TAC can make it tick too.
+ * On overflow TIMA reads 0 for one M-cycle before TMA is loaded and the interrupt
+ * requested. Writing TIMA in that M-cycle cancels the reload, during the M-cycle
+ * of the reload TIMA writes are ignored and TMA writes go through to TIMA.
+ */
+const RELOAD_DELAY: u8 = 4;
+
 pub struct Timer {
     frequency: TimerFrequency,
-    cycles: usize,
+    counter: u16,
     value: u8,
     modulo: u8,
-    active: bool
+    active: bool,
+    reload_delay: u8, //cycles until TMA is loaded after an overflow
+    reloading: u8, //cycles left of the M-cycle TMA was loaded in
 }
 
 
 impl Timer {
-    pub fn new(frequency: TimerFrequency) -> Self {
+    pub fn new() -> Self {
         Timer {
-            frequency: frequency,
-            cycles: 0,
+            frequency: TimerFrequency::F4096,
+            counter: 0,
             value: 0,
             modulo: 0,
-            active: false
+            active: false,
+            reload_delay: 0,
+            reloading: 0,
         }
     }
 
+    /* Returns true when the timer interrupt is requested */
     pub fn step(&mut self, cycles: u16) -> bool {
-        if !self.active {
-            return false;
+        let mut interrupt = false;
+        for _ in 0..cycles {
+            if self.reloading > 0 {
+                self.reloading -= 1;
+            }
+            if self.reload_delay > 0 {
+                self.reload_delay -= 1;
+                if self.reload_delay == 0 {
+                    self.value = self.modulo;
+                    self.reloading = RELOAD_DELAY;
+                    interrupt = true;
+                }
+            }
+
+            let signal = self.signal();
+            self.counter = self.counter.wrapping_add(1);
+            self.check_falling_edge(signal);
         }
+        interrupt
+    }
+
+    fn signal(&self) -> bool {
+        self.active && (self.counter & self.frequency.counter_bit()) != 0
+    }
 
-        self.cycles += cycles as usize;
-        let cycles_per_tick = self.frequency.in_ticks();
-        let did_overflow = if self.cycles > cycles_per_tick {
-            self.cycles = self.cycles % cycles_per_tick;
-            let (new, overflow) = self.value.overflowing_add(1);
-            self.value = new;
-            overflow
+    fn check_falling_edge(&mut self, previous_signal: bool) {
+        if previous_signal && !self.signal() {
+            self.increment();
         }
-        else {
-            false
-        };
+    }
+
+    fn increment(&mut self) {
+        let (new, overflow) = self.value.overflowing_add(1);
+        self.value = new;
+        if overflow {
+            self.reload_delay = RELOAD_DELAY;
+        }
+    }
+
+    pub fn divider(&self) -> u8 {
+        (self.counter >> 8) as u8
+    }
+
+    pub fn reset_divider(&mut self) {
+        let signal = self.signal();
+        self.counter = 0;
+        self.check_falling_edge(signal);
+    }
+
+    pub fn write_value(&mut self, byte: u8) {
+        if self.reloading > 0 {
+            return;
+        }
+        self.value = byte;
+        self.reload_delay = 0;
+    }
 
-        if did_overflow {
-            self.value = self.modulo;
+    pub fn write_modulo(&mut self, byte: u8) {
+        self.modulo = byte;
+        if self.reloading > 0 {
+            self.value = byte;
         }
-        did_overflow
     }
 
+    pub fn control(&self) -> u8 {
+        let freq = match self.frequency {
+           TimerFrequency::F4096 => 0,
+           TimerFrequency::F262144 => 1,
+           TimerFrequency::F65536 => 2,
+           TimerFrequency::F16384 => 3
+        };
+        0b11111000 | (self.active as u8) << 2 | freq
+    }
+
+    pub fn write_control(&mut self, byte: u8) {
+        let signal = self.signal();
+        self.active = ((byte >> 2) & 0b1) == 1;
+        self.frequency = match byte & 0b11 {
+            0 => TimerFrequency::F4096,
+            1 => TimerFrequency::F262144,
+            2 => TimerFrequency::F65536,
+            _ => TimerFrequency::F16384,
+        };
+        self.check_falling_edge(signal);
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.control());
+        state.write_u16(self.counter);
+        state.write_u8(self.value);
+        state.write_u8(self.modulo);
+        state.write_u8(self.reload_delay);
+        state.write_u8(self.reloading);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let control = state.read_u8()?;
+        self.active = ((control >> 2) & 0b1) == 1;
+        self.frequency = match control & 0b11 {
+            0 => TimerFrequency::F4096,
+            1 => TimerFrequency::F262144,
+            2 => TimerFrequency::F65536,
+            _ => TimerFrequency::F16384,
+        };
+        self.counter = state.read_u16()?;
+        self.value = state.read_u8()?;
+        self.modulo = state.read_u8()?;
+        self.reload_delay = state.read_u8()?;
+        self.reloading = state.read_u8()?;
+        if self.reload_delay > RELOAD_DELAY || self.reloading > RELOAD_DELAY {
+            return Err(StateError::InvalidValue("timer reload", self.reload_delay));
+        }
+        Ok(())
+    }
 }
 
 //pub struct IO {
@@ -140,14 +251,12 @@ pub struct MemoryBus {
      * 16kB ROM bank #0             0000-3FFF
      */
     pub boot_rom: Option<[u8; BOOT_ROM_SIZE]>,
-    rom_bank: [u8; ROM_BANK_SIZE],
-    switchable_rom_bank: [u8; ROM_SWITCHABLE_BANK_SIZE],
-    external_ram: [u8; EXTERNAL_RAM_SIZE],
+    pub cartridge_type: CartridgeType,
+    mbc: Box<dyn MemoryBankController>,
     working_ram: [u8; WORKING_RAM_SIZE],
     high_ram: [u8; HRAM_SIZE],
 
     timer: Timer,
-    divider: Timer,
 
     pub joypad: Joypad,
 
@@ -155,34 +264,36 @@ pub struct MemoryBus {
     pub interrupt_flags: Interrupts,
 
     pub gpu: GPU,
+    pub apu: APU,
+    dma: OamDma,
+
+    /* Unmapped IO registers the ROM has touched, each one is only recorded once
+     * and handed to the frontend by take_unhandled_io
+     */
+    unhandled_io: RefCell<HashSet<u16>>,
+    new_unhandled_io: RefCell<Vec<u16>>,
 }
 
 impl MemoryBus {
     pub fn new_empty_memory() -> MemoryBus {
-        MemoryBus::new(None, vec![0; 0x10000])
+        MemoryBus::new(None, Cartridge::empty())
     }
 
-    pub fn new(boot_rom_buffer: Option<Vec<u8>>, game_rom_buffer: Vec<u8>) -> MemoryBus {
+    pub fn new(boot_rom_buffer: Option<Vec<u8>>, cartridge: Cartridge) -> MemoryBus {
         let boot_rom = boot_rom_buffer.map(|boot_rom_data| {
             let mut boot_rom = [0; BOOT_ROM_SIZE];
             boot_rom.copy_from_slice(&boot_rom_data);
             boot_rom
         });
 
-        let mut rom_bank = [0xFF; ROM_BANK_SIZE];
-        rom_bank.copy_from_slice(&game_rom_buffer[..=ROM_BANK_END]);
-
-        let mut switchable_rom_bank = [0xFF; ROM_SWITCHABLE_BANK_SIZE];
-        switchable_rom_bank.copy_from_slice(&game_rom_buffer[ROM_SWITCHABLE_BANK_START..=ROM_SWITCHABLE_BANK_END]);
-
+        let cartridge_type = cartridge.cartridge_type;
+        let ram_size = cartridge.ram_size();
+        let mbc = mbc::new(cartridge_type, cartridge.data, ram_size);
 
-        let mut divider = Timer::new(TimerFrequency::F16384);
-        divider.active = true;
         MemoryBus {
             boot_rom,
-            rom_bank,
-            switchable_rom_bank,
-            external_ram: [0xFF; EXTERNAL_RAM_SIZE],
+            cartridge_type,
+            mbc,
             working_ram: [0xFF; WORKING_RAM_SIZE],
             high_ram: [0xFF; HRAM_SIZE],
 
@@ -191,18 +302,29 @@ impl MemoryBus {
 
             joypad: Joypad::new(),
 
-            timer: Timer::new(TimerFrequency::F4096),
-            divider,
+            timer: Timer::new(),
             gpu: GPU::new(),
+            apu: APU::new(DEFAULT_SAMPLE_RATE),
+            dma: OamDma::new(),
+
+            unhandled_io: RefCell::new(HashSet::new()),
+            new_unhandled_io: RefCell::new(Vec::new()),
         }
     }
 
     pub fn step(&mut self, cycles: u16) {
+        let divider = self.timer.divider();
         if self.timer.step(cycles) {
             self.interrupt_flags.timer = true;
         }
+        self.clock_frame_sequencer(divider);
 
-        self.divider.step(cycles);
+        if self.joypad.check_interrupt() {
+            self.interrupt_flags.joypad = true;
+        }
+        self.apu.step(cycles);
+        self.mbc.step(cycles);
+        self.step_dma(cycles);
 
         let (vblank, lcd) = self.gpu.step(cycles);
         if vblank {
@@ -213,6 +335,124 @@ impl MemoryBus {
         }
     }
 
+    pub fn reset_divider(&mut self) {
+        let divider = self.timer.divider();
+        self.timer.reset_divider();
+        self.clock_frame_sequencer(divider);
+    }
+
+    /* DMA has its own path to OAM, the CPU restrictions don't apply */
+    fn step_dma(&mut self, cycles: u16) {
+        for (address, offset) in self.dma.step(cycles) {
+            let value = self.dma_read(address);
+            self.gpu.write_oam(offset, value);
+            self.dma.value = value;
+        }
+    }
+
+    /* DMA has its own path to VRAM, the PPU mode only locks out the CPU */
+    fn dma_read(&self, address: u16) -> u8 {
+        let address = address as usize;
+        match address {
+            VIDEO_RAM_START..=VIDEO_RAM_END => self.gpu.video_ram[address - VIDEO_RAM_START],
+            _ => self.read_memory(address as u16),
+        }
+    }
+
+    /* While DMA runs the CPU only reaches HRAM and the IO registers. A read from the
+     * bus DMA is using returns the byte being copied, anything else reads 0xFF.
+     */
+    fn dma_blocks(&self, address: u16) -> bool {
+        self.dma.active() && (address as usize) < IO_REGISTERS_START
+    }
+
+    fn dma_conflict_read(&self, address: u16) -> u8 {
+        let video_bus = |address: u16| {
+            (VIDEO_RAM_START..=VIDEO_RAM_END).contains(&(address as usize))
+        };
+        let external_bus = |address: u16| (address as usize) < OAM_START && !video_bus(address);
+
+        let source = self.dma.source();
+        if (video_bus(address) && video_bus(source)) ||
+            (external_bus(address) && external_bus(source)) {
+            self.dma.value
+        } else {
+            0xFF
+        }
+    }
+
+    /* The frame sequencer runs off the falling edge of DIV bit 4, which a DIV reset can cause too */
+    fn clock_frame_sequencer(&mut self, previous_divider: u8) {
+        if (previous_divider & 0x10) != 0 && (self.timer.divider() & 0x10) == 0 {
+            self.apu.clock_frame_sequencer();
+        }
+    }
+
+    pub fn sync_rtc_to_host(&mut self, sync: bool) {
+        self.mbc.sync_rtc_to_host(sync);
+    }
+
+    pub fn battery_backed(&self) -> bool {
+        self.cartridge_type.battery
+    }
+
+    pub fn save_data(&self) -> Vec<u8> {
+        self.mbc.save_data()
+    }
+
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        self.mbc.load_save_data(data);
+    }
+
+    pub fn save_requested(&mut self) -> bool {
+        self.mbc.take_save_request()
+    }
+
+    pub fn rumble(&self) -> bool {
+        self.mbc.rumble()
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.cartridge_type.code);
+        state.write_bool(self.boot_rom.is_some());
+        if let Some(boot_rom) = self.boot_rom {
+            state.write_bytes(&boot_rom);
+        }
+        state.write_bytes(&self.working_ram);
+        state.write_bytes(&self.high_ram);
+        state.write_u8(self.interrupts_enabled.to_byte());
+        state.write_u8(self.interrupt_flags.to_byte());
+        self.joypad.save_state(state);
+        self.timer.save_state(state);
+        self.gpu.save_state(state);
+        self.apu.save_state(state);
+        self.dma.save_state(state);
+        self.mbc.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        if state.read_u8()? != self.cartridge_type.code {
+            return Err(StateError::CartridgeMismatch);
+        }
+        self.boot_rom = if state.read_bool()? {
+            let mut boot_rom = [0; BOOT_ROM_SIZE];
+            state.read_bytes(&mut boot_rom)?;
+            Some(boot_rom)
+        } else {
+            None
+        };
+        state.read_bytes(&mut self.working_ram)?;
+        state.read_bytes(&mut self.high_ram)?;
+        self.interrupts_enabled.from_byte(state.read_u8()?);
+        self.interrupt_flags.from_byte(state.read_u8()?);
+        self.joypad.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.gpu.load_state(state)?;
+        self.apu.load_state(state)?;
+        self.dma.load_state(state)?;
+        self.mbc.load_state(state)
+    }
+
     pub fn interrupted(&self) -> bool {
         return
         (self.interrupts_enabled.vertical_blank &&
@@ -227,50 +467,102 @@ impl MemoryBus {
             self.interrupt_flags.joypad);
     }
 
+    /* Clears and returns the highest priority interrupt that is enabled and requested,
+     * VBlank first and joypad last
+     */
+    pub fn take_interrupt(&mut self) -> Option<InterruptLocation> {
+        let enabled = &self.interrupts_enabled;
+        let flags = &mut self.interrupt_flags;
+        if enabled.vertical_blank && flags.vertical_blank {
+            flags.vertical_blank = false;
+            Some(InterruptLocation::VBlank)
+        } else if enabled.lcd_c && flags.lcd_c {
+            flags.lcd_c = false;
+            Some(InterruptLocation::LCD)
+        } else if enabled.timer && flags.timer {
+            flags.timer = false;
+            Some(InterruptLocation::Timer)
+        } else if enabled.serial_transfer && flags.serial_transfer {
+            flags.serial_transfer = false;
+            Some(InterruptLocation::Serial)
+        } else if enabled.joypad && flags.joypad {
+            flags.joypad = false;
+            Some(InterruptLocation::Joypad)
+        } else {
+            None
+        }
+    }
+
     pub fn read_byte(&self, address: u16) -> u8 {
+        if self.dma_blocks(address) {
+            return self.dma_conflict_read(address);
+        }
+        self.read_memory(address)
+    }
+
+    fn read_memory(&self, address: u16) -> u8 {
         let address = address as usize;
         match address {
             BOOT_ROM_START...BOOT_ROM_END => {
                 if let Some(boot_rom) = self.boot_rom {
                     boot_rom[address]
                 } else {
-                    self.rom_bank[address]
+                    self.mbc.read_rom(address as u16)
                 }
             }
-            ROM_BANK_START...ROM_BANK_END => self.rom_bank[address],
+            ROM_BANK_START..=ROM_BANK_END => self.mbc.read_rom(address as u16),
             ROM_SWITCHABLE_BANK_START...ROM_SWITCHABLE_BANK_END => {
-                self.switchable_rom_bank[address - ROM_SWITCHABLE_BANK_START]
+                self.mbc.read_rom(address as u16)
             }
             VIDEO_RAM_START...VIDEO_RAM_END => {
+                if !self.gpu.cpu_may_access_vram() {
+                    return 0xFF;
+                }
                 self.gpu.video_ram[address - VIDEO_RAM_START]
             },
             EXTERNAL_RAM_START...EXTERNAL_RAM_END => {
-                self.external_ram[address - EXTERNAL_RAM_START]
+                self.mbc.read_ram((address - EXTERNAL_RAM_START) as u16)
             }
             WORKING_RAM_START...WORKING_RAM_END => self.working_ram[address - WORKING_RAM_START],
             ECHO_RAM_START...ECHO_RAM_END => self.working_ram[address - ECHO_RAM_START],
-            OAM_START...OAM_END => self.gpu.oam[address - OAM_START],
+            OAM_START...OAM_END => {
+                if !self.gpu.cpu_may_access_oam() {
+                    return 0xFF;
+                }
+                self.gpu.oam[address - OAM_START]
+            }
             IO_REGISTERS_START...IO_REGISTERS_END => self.read_from_io(address),
             HRAM_START...HRAM_END => self.high_ram[address - HRAM_START],
             ENABLE_INTERRUPTS => { return self.interrupts_enabled.to_byte(); }
-            _ => {
-                panic!("Error reading from memory location 0x{:X}", address);
+            //Unusable area, the DMG reads 0 unless the PPU has OAM
+            UNUSED_START...UNUSED_END => {
+                if !self.gpu.cpu_may_access_oam() {
+                    return 0xFF;
+                }
+                0x00
             }
+            _ => 0xFF,
         }
     }
 
     pub fn write_byte(&mut self, address: u16, byte: u8) {
+        if self.dma_blocks(address) {
+            return;
+        }
         let address = address as usize;
         match address {
-            ROM_BANK_START...ROM_BANK_END => self.rom_bank[address] = byte,
+            ROM_BANK_START...ROM_BANK_END => self.mbc.write_rom(address as u16, byte),
             ROM_SWITCHABLE_BANK_START...ROM_SWITCHABLE_BANK_END => {
-                self.switchable_rom_bank[address - ROM_SWITCHABLE_BANK_START] = byte
+                self.mbc.write_rom(address as u16, byte)
             }
             VIDEO_RAM_START...VIDEO_RAM_END => {
+                if !self.gpu.cpu_may_access_vram() {
+                    return;
+                }
                 self.gpu.write_vram(address - VIDEO_RAM_START, byte)
             }
             EXTERNAL_RAM_START...EXTERNAL_RAM_END => {
-                self.external_ram[address - EXTERNAL_RAM_START] = byte
+                self.mbc.write_ram((address - EXTERNAL_RAM_START) as u16, byte)
             }
             WORKING_RAM_START...WORKING_RAM_END => {
                 self.working_ram[address - WORKING_RAM_START] = byte
@@ -279,6 +571,9 @@ impl MemoryBus {
                 self.working_ram[address - ECHO_RAM_START] = byte
             }
             OAM_START...OAM_END => {
+                if !self.gpu.cpu_may_access_oam() {
+                    return;
+                }
                 self.gpu.write_oam(address - OAM_START, byte)
             }
             IO_REGISTERS_START...IO_REGISTERS_START => {
@@ -294,7 +589,7 @@ impl MemoryBus {
             ENABLE_INTERRUPTS => {
                 self.interrupts_enabled.from_byte(byte);
             },
-            _ => panic!("Error writing to memory location 0x{:X}", address),
+            _ => {}
         };
     }
 
@@ -308,21 +603,15 @@ impl MemoryBus {
             0xFF01 => { /* SB - Serial transfer data */ }
             0xFF02 => { /* SC - Serial transfer control */ }
 
-            0xFF04 => { return self.divider.value; }
+            0xFF04 => { return self.timer.divider(); }
             0xFF05 => { return self.timer.value; }
             0xFF06 => { return self.timer.modulo;  }
-            0xFF07 => {
-                let freq = match self.timer.frequency {
-                   TimerFrequency::F4096 => 0,
-                   TimerFrequency::F262144 => 1,
-                   TimerFrequency::F65536 => 2,
-                   TimerFrequency::F16384 => 3
-                };
-                return (self.timer.active as u8) << 2 | freq;
-            }
+            0xFF07 => { return self.timer.control(); }
 
             0xFF0F => { return self.interrupt_flags.to_byte(); }
 
+            0xFF10..=0xFF3F => { return self.apu.read_register(address as u16); }
+
             0xFF40 => {
                 return
                     (self.gpu.lcd_display_enabled as u8)                                << 7 |
@@ -355,11 +644,19 @@ impl MemoryBus {
             0xFF43 => { return self.gpu.scroll_x; }
             0xFF44 => { return self.gpu.lcd_y_coordinate; }
             0xFF45 => { return self.gpu.lcd_y_compare; }
+            0xFF46 => { return self.dma.register; }
+            0xFF47 => { return self.gpu.background_window_palette.into(); }
+            0xFF48 => { return self.gpu.obj_0_palette.into(); }
+            0xFF49 => { return self.gpu.obj_1_palette.into(); }
+            0xFF4A => { return self.gpu.window_y; }
+            0xFF4B => { return self.gpu.window_x; }
 
             0xFF4D => { return 0; }
 
             _ => {
-                panic!("Error reading from IO at 0x{:X}", address);
+                //Unmapped registers float high
+                self.record_unhandled_io(address);
+                return 0xFF;
             }
         }
         return 0;
@@ -369,9 +666,7 @@ impl MemoryBus {
         match address {
             0xFF00 => {
                 /* P1 - joy pad info */
-                //let query_dpad = ((byte >> 4) & 0b1) == 1;
-                //let query_buttons = ((byte >> 5) & 0b1) == 1;
-                self.joypad.column = (byte & 0x20) == 0;
+                self.joypad.write(byte);
             }
 
             0xFF01 => {
@@ -380,26 +675,21 @@ impl MemoryBus {
             }
             0xFF02 => { /* SC - Serial transfer control */ }
 
-            0xFF04 => { self.divider.value = 0; }
+            0xFF04 => {
+                /* DIV - Divider, any write resets the whole system counter */
+                self.reset_divider();
+            }
             0xFF05 => {
                 /* TIMA - Timer Counter */
-                self.timer.value = byte;
+                self.timer.write_value(byte);
             }
             0xFF06 => {
                 /* TMA - Timer modulo */
-                self.timer.modulo = byte;
+                self.timer.write_modulo(byte);
             }
             0xFF07 => {
                 /* TAC - Timer control */
-                self.timer.active = ((byte >> 2) & 0b1) == 1;
-                let clock_select = byte & 0b11;
-                self.timer.frequency = match clock_select {
-                    0 => TimerFrequency::F4096,
-                    1 => TimerFrequency::F262144,
-                    2 => TimerFrequency::F65536,
-                    3 => TimerFrequency::F16384,
-                    _ => panic!("Incorrect timer frequency")
-                };
+                self.timer.write_control(byte);
             }
 
             0xFF0F => {
@@ -407,36 +697,14 @@ impl MemoryBus {
                 self.interrupt_flags.from_byte(byte);
             }
 
-            0xFF10 => { /* NR 10 - Sound Mode 1 Sweep register */ }
-            0xFF11 => { /* NR 11 - Sound Mode 1 Length wave pattern duty*/ }
-            0xFF12 => { /* NR 12 - Sound Mode 1 Volume Envelope */ }
-            0xFF13 => { /* NR 13 - Sound Mode 1 lo Frequency data Write only */ }
-            0xFF14 => { /* NR 14 - Sound Mode 1 hi Frequency data */ }
-
-            0xFF16 => { /* NR 21 - Sound Mode 2 Length wave pattern duty */ }
-            0xFF17 => { /* NR 22 - Sound Mode 2 Volume Envelope */ }
-            0xFF18 => { /* NR 23 - Sound Mode 2 lo Frequency data Write only */ }
-            0xFF19 => { /* NR 24 - Sound Mode 2 hi Frequency data */ }
-
-            0xFF1A => { /* NR 30 - Sound Mode 3 sound on/off */ }
-            0xFF1B => { /* NR 31 - Sound Mode 3 sound length */ }
-            0xFF1C => { /* NR 32 - Sound Mode 3 select ouput level */ }
-            0xFF1D => { /* NR 33 - Sound Mode 3 lo Frequency data Write only*/ }
-            0xFF1E => { /* NR 34 - Sound Mode 3 hi Frequency data */ }
-
-            0xFF20 => { /* NR 41 - Sound Mode 4 Sound length */ }
-            0xFF21 => { /* NR 42 - Sound Mode 4 Volume Envelope */ }
-            0xFF22 => { /* NR 43 - Sound Mode 4 Polynomial counter */ }
-            0xFF23 => { /* NR 44 - Sound Mode 4 counter/consecutive */ }
-            0xFF24 => { /* NR 50 - Channel control / ON-OFF / Volume */ }
-            0xFF25 => { /* NR 51 - Sound output terminal */ }
-            0xFF26 => { /* NR 52 - Sound on/off */ }
-
-            0xFF30...0xFF3F => { /* Wave Pattern RAM */ } //FIXME: find documentation for this
+            0xFF10..=0xFF3F => {
+                /* NR10-NR52 - Sound registers, 0xFF30-0xFF3F Wave pattern RAM */
+                self.apu.write_register(address as u16, byte);
+            }
 
             0xFF40 => {
                 //LCDC - LCD Control
-                self.gpu.lcd_display_enabled = (byte >> 7) == 1;
+                self.gpu.set_lcd_enabled((byte >> 7) == 1);
                 self.gpu.window_tile_map = if ((byte >> 6) & 0b1) == 1 {
                     TileMap::Ox9C00
                 } else {
@@ -464,11 +732,10 @@ impl MemoryBus {
 
             0xFF41 => {
                 /* STAT - LCDC Status */
-                //interrupt select:
-                self.gpu.lyc_interrupt_enabled = ((byte >> 6) & 0b1) == 1;
-                self.gpu.oam_interrupt_enabled = ((byte >> 5) & 0b1) == 1;
-                self.gpu.vblank_interrupt_enabled = ((byte >> 4) & 0b1) == 1;
-                self.gpu.hblank_interrupt_enabled = ((byte >> 3) & 0b1) == 1;
+                //interrupt select, the write itself can request an interrupt
+                if self.gpu.write_stat(byte) {
+                    self.interrupt_flags.lcd_c = true;
+                }
             }
 
             0xFF42 => {
@@ -485,15 +752,8 @@ impl MemoryBus {
                 self.gpu.lcd_y_compare = byte;
             }
             0xFF46 => {
-                /* DMA - DMA Transfer and Start Address Write only*/
-                let dma_source = (byte as u16) << 8;
-                let dma_destination = 0xFE00;
-                for offset in 0..150 {
-                    self.write_byte(
-                        dma_destination + offset,
-                        self.read_byte(dma_source + offset),
-                    )
-                }
+                /* DMA - DMA Transfer and Start Address */
+                self.dma.start(byte);
             }
 
             0xFF47 => {
@@ -529,12 +789,31 @@ impl MemoryBus {
             0xFF69 => { /* GBC register */ }
 
             0xFF7F => {}
-            _ => {
-                panic!("Error writing to IO at 0x{:X}", address);
-            }
+            _ => self.record_unhandled_io(address),
         };
     }
 
+    fn record_unhandled_io(&self, address: usize) {
+        if self.unhandled_io.borrow_mut().insert(address as u16) {
+            self.new_unhandled_io.borrow_mut().push(address as u16);
+        }
+    }
+
+    /* Unmapped IO registers accessed for the first time since the last call */
+    pub fn take_unhandled_io(&mut self) -> Vec<u16> {
+        std::mem::take(self.new_unhandled_io.get_mut())
+    }
+
+    pub fn load_save_file(&mut self, path: &Path) -> io::Result<()> {
+        let data = fs::read(path)?;
+        self.load_save_data(&data);
+        Ok(())
+    }
+
+    pub fn write_save_file(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.save_data())
+    }
+
     pub fn dump_memory_to_file(&self) {
         print!("Dumping...");
         let mut ram = File::create("./RAM.bin").unwrap();
@@ -546,37 +825,81 @@ impl MemoryBus {
 #[cfg(test)]
 mod tests {
 
-    mod timerTests {
+    mod timer_tests {
         use super::*;
         #[test]
         fn timer_overflows() {
             let mut timer = Timer::new();
-            timer.active = true;
+            timer.write_control(0b100);
             timer.modulo = 128;
-            let overflow = timer.step(1024);
+            assert!(!timer.step(1023));
             assert_eq!(timer.value, 0);
-            let overflow = timer.step(1);
+            assert!(!timer.step(1));
             assert_eq!(timer.value, 1);
-            assert_eq!(timer.cycles, 1);
-            assert_eq!(overflow, false);
+            assert_eq!(timer.counter, 1024);
             timer.value = 255;
-            let overflow = timer.step(1024);
-            assert_eq!(overflow, true);
+            assert!(!timer.step(1024));
+            assert_eq!(timer.value, 0);
+            assert!(timer.step(4));
             assert_eq!(timer.value, 128);
         }
+
+        #[test]
+        fn tima_write_cancels_reload() {
+            let mut timer = Timer::new();
+            timer.write_control(0b101);
+            timer.modulo = 0x40;
+            timer.value = 0xFF;
+            timer.step(16);
+            timer.write_value(0x12);
+            assert!(!timer.step(4));
+            assert_eq!(timer.value, 0x12);
+        }
+
+        #[test]
+        fn writes_in_reload_cycle() {
+            let mut timer = Timer::new();
+            timer.write_control(0b101);
+            timer.modulo = 0x40;
+            timer.value = 0xFF;
+            timer.step(16);
+            assert!(timer.step(4));
+            timer.write_value(0x12);
+            assert_eq!(timer.value, 0x40);
+            timer.write_modulo(0x50);
+            assert_eq!(timer.value, 0x50);
+        }
+
+        #[test]
+        fn div_reset_and_tac_change_glitches() {
+            let mut timer = Timer::new();
+            timer.write_control(0b100);
+            timer.step(512);
+            timer.reset_divider();
+            assert_eq!(timer.value, 1);
+            assert_eq!(timer.divider(), 0);
+
+            timer.step(512);
+            timer.write_control(0b000);
+            assert_eq!(timer.value, 2);
+            //Bit 3 is 0 at this point, switching to it makes TIMA tick
+            timer.write_control(0b100);
+            timer.write_control(0b101);
+            assert_eq!(timer.value, 3);
+        }
     }
 
     use super::*;
-    use crate::gpu::{Color};
+    use crate::gpu::{Color, Renderer};
 
     #[test]
     fn divider_zeroed() {
         let mut mem = MemoryBus::new_empty_memory();
-        mem.step(10);
-        assert_eq!(mem.divider.value, 10);
+        mem.step(10 * 256);
+        assert_eq!(mem.timer.divider(), 10);
         assert_eq!(mem.read_byte(0xFF04), 10);
         mem.write_byte(0xFF04, 1);
-        assert_eq!(mem.divider.value, 0);
+        assert_eq!(mem.timer.divider(), 0);
     }
 
     #[test]
@@ -598,4 +921,101 @@ mod tests {
         assert_eq!(mem.gpu.background_window_palette.2 as u8, Color::Black as u8);
         assert_eq!(mem.gpu.background_window_palette.3 as u8, Color::Black as u8);
     }
+
+    #[test]
+    fn vram_and_oam_blocked_while_in_use() {
+        let mut mem = MemoryBus::new_empty_memory();
+        mem.write_byte(0x8000, 0x12);
+        mem.gpu.lcd_mode = Mode::VRAMAccess;
+        assert_eq!(mem.read_byte(0x8000), 0x12);
+
+        let mut mem = MemoryBus::new_empty_memory();
+        mem.gpu = GPU::with_options(Renderer::Scanline, true);
+        mem.write_byte(0x8000, 0x12);
+        mem.write_byte(0xFE00, 0x34);
+        mem.gpu.lcd_mode = Mode::VRAMAccess;
+        assert_eq!(mem.read_byte(0x8000), 0xFF);
+        assert_eq!(mem.read_byte(0xFE00), 0xFF);
+        mem.write_byte(0x8000, 0x56);
+
+        mem.gpu.lcd_mode = Mode::OAMAccess;
+        assert_eq!(mem.read_byte(0x8000), 0x12);
+        assert_eq!(mem.read_byte(0xFE00), 0xFF);
+        mem.write_byte(0xFE00, 0x78);
+
+        mem.gpu.lcd_mode = Mode::HBlank;
+        assert_eq!(mem.read_byte(0xFE00), 0x34);
+    }
+
+    #[test]
+    fn oam_dma_from_vram_ignores_ppu_mode() {
+        let mut mem = MemoryBus::new_empty_memory();
+        mem.gpu = GPU::with_options(Renderer::Scanline, true);
+        for offset in 0..0xA0 {
+            mem.write_byte(0x8000 + offset, offset as u8 + 1);
+        }
+        mem.gpu.lcd_mode = Mode::VRAMAccess;
+        mem.write_byte(0xFF46, 0x80);
+        mem.step_dma(4 * 161);
+        assert!(!mem.dma.active());
+        assert_eq!(mem.gpu.oam[0], 0x01);
+        assert_eq!(mem.gpu.oam[0x9F], 0xA0);
+    }
+
+    #[test]
+    fn oam_dma_runs_for_160_m_cycles() {
+        let mut mem = MemoryBus::new_empty_memory();
+        for offset in 0..0xA0 {
+            mem.write_byte(0xC100 + offset, offset as u8);
+        }
+        mem.write_byte(0xFF80, 0x42);
+        mem.write_byte(0xFF46, 0xC1);
+        assert_eq!(mem.read_byte(0xC105), 0x05);
+        mem.step(4);
+
+        //Only HRAM and IO are reachable, WRAM reads see the byte being copied
+        mem.step(4 * 10);
+        assert_eq!(mem.read_byte(0xFF80), 0x42);
+        assert_eq!(mem.read_byte(0xFF46), 0xC1);
+        assert_eq!(mem.read_byte(0xC105), 0x09);
+        assert_eq!(mem.read_byte(0x8000), 0xFF);
+        mem.write_byte(0xC000, 0x12);
+
+        mem.step(4 * 149);
+        assert_eq!(mem.read_byte(0xC000), 0x9E);
+        mem.step(4);
+        assert_eq!(mem.read_byte(0xC000), 0xFF);
+        assert_eq!(mem.gpu.oam[0], 0x00);
+        assert_eq!(mem.gpu.oam[0x9F], 0x9F);
+    }
+
+    #[test]
+    fn unmapped_reads_and_writes_are_ignored() {
+        let mut mem = MemoryBus::new_empty_memory();
+        mem.write_byte(0xFF03, 0x12);
+        mem.write_byte(0xFEA0, 0x12);
+        assert_eq!(mem.read_byte(0xFF03), 0xFF);
+        assert_eq!(mem.read_byte(0xFF7E), 0xFF);
+        assert_eq!(mem.read_byte(0xFEA0), 0x00);
+        assert_eq!(mem.take_unhandled_io(), vec![0xFF03, 0xFF7E]);
+
+        mem.read_byte(0xFF03);
+        assert!(mem.take_unhandled_io().is_empty());
+    }
+
+    #[test]
+    fn gpu_registers_read_back() {
+        let mut mem = MemoryBus::new_empty_memory();
+        mem.write_byte(0xFF47, 0xE4);
+        mem.write_byte(0xFF48, 0xD2);
+        mem.write_byte(0xFF49, 0x1B);
+        mem.write_byte(0xFF4A, 0x40);
+        mem.write_byte(0xFF4B, 0x07);
+        assert_eq!(mem.read_byte(0xFF47), 0xE4);
+        assert_eq!(mem.read_byte(0xFF48), 0xD2);
+        assert_eq!(mem.read_byte(0xFF49), 0x1B);
+        assert_eq!(mem.read_byte(0xFF4A), 0x40);
+        assert_eq!(mem.read_byte(0xFF4B), 0x07);
+        assert!(mem.take_unhandled_io().is_empty());
+    }
 }
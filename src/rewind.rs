@@ -0,0 +1,209 @@
+use std::collections::VecDeque;
+
+use crate::cpu::CPU;
+use crate::state::StateError;
+
+/* Rewind history
+ * A full copy of the newest snapshot is kept, every older snapshot is stored as
+ * the difference to the one taken after it. Consecutive states differ in a small
+ * part of memory so the difference is XORed and the zero runs are packed:
+ * u32 number of unchanged bytes, u32 number of changed bytes, changed bytes XOR new
+ * Stepping back applies the newest difference to the full copy, the oldest
+ * differences are dropped first once the memory budget is used up.
+ */
+pub struct RewindBuffer {
+    interval: u32,
+    frames_until_snapshot: u32,
+    max_bytes: usize,
+    used_bytes: usize,
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl RewindBuffer {
+    /* Takes a snapshot every `interval` frames and uses at most `max_bytes` of memory */
+    pub fn new(interval: u32, max_bytes: usize) -> RewindBuffer {
+        RewindBuffer {
+            interval: std::cmp::max(interval, 1),
+            frames_until_snapshot: 0,
+            max_bytes,
+            used_bytes: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /* Called once per emulated frame */
+    pub fn frame(&mut self, cpu: &CPU) {
+        if self.frames_until_snapshot == 0 {
+            self.push(cpu.save_state());
+            self.frames_until_snapshot = self.interval;
+        }
+        self.frames_until_snapshot -= 1;
+    }
+
+    /* Restores the newest snapshot and forgets it, false when the history is empty */
+    pub fn rewind(&mut self, cpu: &mut CPU) -> Result<bool, StateError> {
+        match self.pop() {
+            Some(state) => {
+                cpu.load_state(&state)?;
+                self.frames_until_snapshot = self.interval;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(latest) = self.latest.take() {
+            self.used_bytes -= latest.len();
+            if latest.len() == state.len() {
+                let delta = encode_delta(&state, &latest);
+                self.used_bytes += delta.len();
+                self.deltas.push_back(delta);
+            } else {
+                self.clear();
+            }
+        }
+        self.used_bytes += state.len();
+        self.latest = Some(state);
+
+        while self.used_bytes > self.max_bytes {
+            match self.deltas.pop_front() {
+                Some(delta) => self.used_bytes -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let state = self.latest.take()?;
+        self.used_bytes -= state.len();
+        if let Some(delta) = self.deltas.pop_back() {
+            self.used_bytes -= delta.len();
+            let mut previous = state.clone();
+            apply_delta(&mut previous, &delta);
+            self.used_bytes += previous.len();
+            self.latest = Some(previous);
+        }
+        Some(state)
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.used_bytes = 0;
+    }
+
+    /* Number of snapshots that can be stepped back through */
+    pub fn len(&self) -> usize {
+        match self.latest {
+            Some(_) => self.deltas.len() + 1,
+            None => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    pub fn used_bytes(&self) -> usize {
+        self.used_bytes
+    }
+}
+
+fn encode_delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let mut position = 0;
+    while position < from.len() {
+        let unchanged = from[position..].iter()
+            .zip(to[position..].iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        position += unchanged;
+        let changed = from[position..].iter()
+            .zip(to[position..].iter())
+            .take_while(|(a, b)| a != b)
+            .count();
+
+        delta.extend_from_slice(&(unchanged as u32).to_le_bytes());
+        delta.extend_from_slice(&(changed as u32).to_le_bytes());
+        let range = position..position + changed;
+        delta.extend(from[range.clone()].iter().zip(to[range].iter()).map(|(a, b)| a ^ b));
+        position += changed;
+    }
+    delta
+}
+
+fn apply_delta(state: &mut [u8], delta: &[u8]) {
+    let read_u32 = |offset: usize| {
+        u32::from_le_bytes([delta[offset], delta[offset + 1], delta[offset + 2], delta[offset + 3]])
+            as usize
+    };
+
+    let mut position = 0;
+    let mut offset = 0;
+    while offset < delta.len() {
+        position += read_u32(offset);
+        let changed = read_u32(offset + 4);
+        offset += 8;
+        for byte in &mut state[position..position + changed] {
+            *byte ^= delta[offset];
+            offset += 1;
+        }
+        position += changed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_round_trip() {
+        let old = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        let new = vec![1, 2, 0, 0, 5, 6, 7, 9];
+        let delta = encode_delta(&new, &old);
+        let mut restored = new.clone();
+        apply_delta(&mut restored, &delta);
+        assert_eq!(restored, old);
+    }
+
+    #[test]
+    fn pops_newest_first() {
+        let mut rewind = RewindBuffer::new(1, 1024);
+        rewind.push(vec![0; 16]);
+        rewind.push(vec![1; 16]);
+        rewind.push(vec![2; 16]);
+        assert_eq!(rewind.len(), 3);
+        assert_eq!(rewind.pop(), Some(vec![2; 16]));
+        assert_eq!(rewind.pop(), Some(vec![1; 16]));
+        assert_eq!(rewind.pop(), Some(vec![0; 16]));
+        assert_eq!(rewind.pop(), None);
+        assert_eq!(rewind.used_bytes(), 0);
+    }
+
+    #[test]
+    fn drops_oldest_over_budget() {
+        //32 bytes for the newest state and 40 bytes for each difference
+        let mut rewind = RewindBuffer::new(1, 128);
+        for i in 0..10 {
+            rewind.push(vec![i; 32]);
+        }
+        assert!(rewind.used_bytes() <= 128);
+        assert_eq!(rewind.pop(), Some(vec![9; 32]));
+        assert_eq!(rewind.pop(), Some(vec![8; 32]));
+        assert_eq!(rewind.pop(), Some(vec![7; 32]));
+        assert_eq!(rewind.pop(), None);
+    }
+
+    #[test]
+    fn snapshots_every_interval() {
+        let cpu = CPU::new(None, crate::cartridge::Cartridge::empty());
+        let mut rewind = RewindBuffer::new(3, 1 << 20);
+        for _ in 0..7 {
+            rewind.frame(&cpu);
+        }
+        assert_eq!(rewind.len(), 3);
+    }
+}
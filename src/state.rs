@@ -0,0 +1,222 @@
+use std::fmt;
+
+/* Save state layout:
+ * 4 bytes magic "ERKI"
+ * u16 format version
+ * u32 payload length
+ * payload, written by each component in a fixed order, all values little endian
+ *
+ * Bump STATE_VERSION whenever a component changes what it writes.
+ */
+const STATE_MAGIC: &[u8; 4] = b"ERKI";
+pub const STATE_VERSION: u16 = 12;
+const HEADER_SIZE: usize = 10;
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    UnexpectedEnd,
+    LengthMismatch,
+    CartridgeMismatch,
+    InvalidValue(&'static str, u8),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::InvalidMagic => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(version) => write!(
+                f, "Unsupported save state version {}, expected {}", version, STATE_VERSION),
+            StateError::UnexpectedEnd => write!(f, "Save state ended unexpectedly"),
+            StateError::LengthMismatch => write!(
+                f, "Save state does not match the memory layout of this machine"),
+            StateError::CartridgeMismatch => write!(
+                f, "Save state was made with a different cartridge type"),
+            StateError::InvalidValue(field, value) => write!(
+                f, "Invalid value 0x{:02X} for {} in save state", value, field),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /* Fixed size memory regions, the reader must know the size */
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /* Memory regions that depend on the cartridge, prefixed with their length */
+    pub fn write_sized_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.write_bytes(bytes);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        let mut state = Vec::with_capacity(HEADER_SIZE + self.data.len());
+        state.extend_from_slice(STATE_MAGIC);
+        state.extend_from_slice(&STATE_VERSION.to_le_bytes());
+        state.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        state.extend_from_slice(&self.data);
+        state
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    /* Validates the header so a payload is only handed out if it is complete */
+    pub fn new(state: &'a [u8]) -> Result<StateReader<'a>, StateError> {
+        if state.len() < HEADER_SIZE {
+            return Err(StateError::UnexpectedEnd);
+        }
+        if &state[0..4] != STATE_MAGIC {
+            return Err(StateError::InvalidMagic);
+        }
+        let version = u16::from_le_bytes([state[4], state[5]]);
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let length = u32::from_le_bytes([state[6], state[7], state[8], state[9]]) as usize;
+        if state.len() - HEADER_SIZE != length {
+            return Err(StateError::UnexpectedEnd);
+        }
+
+        Ok(StateReader {
+            data: &state[HEADER_SIZE..],
+            position: 0,
+        })
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        if self.position + length > self.data.len() {
+            return Err(StateError::UnexpectedEnd);
+        }
+        let bytes = &self.data[self.position..self.position + length];
+        self.position += length;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn read_bytes(&mut self, target: &mut [u8]) -> Result<(), StateError> {
+        let bytes = self.take(target.len())?;
+        target.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /* Reads a length prefixed region that has to match the size of target */
+    pub fn read_sized_bytes(&mut self, target: &mut [u8]) -> Result<(), StateError> {
+        if self.read_u32()? as usize != target.len() {
+            return Err(StateError::LengthMismatch);
+        }
+        self.read_bytes(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0x12);
+        writer.write_bool(true);
+        writer.write_u16(0x3456);
+        writer.write_u32(0x789ABCDE);
+        writer.write_u64(0x0123456789ABCDEF);
+        writer.write_sized_bytes(&[1, 2, 3]);
+        let state = writer.finish();
+
+        let mut reader = StateReader::new(&state).unwrap();
+        assert_eq!(reader.read_u8(), Ok(0x12));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read_u16(), Ok(0x3456));
+        assert_eq!(reader.read_u32(), Ok(0x789ABCDE));
+        assert_eq!(reader.read_u64(), Ok(0x0123456789ABCDEF));
+        let mut bytes = [0; 3];
+        assert_eq!(reader.read_sized_bytes(&mut bytes), Ok(()));
+        assert_eq!(bytes, [1, 2, 3]);
+        assert_eq!(reader.read_u8(), Err(StateError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn rejects_bad_header() {
+        assert_eq!(StateReader::new(b"NOPE\x01\x00\x00\x00\x00\x00").err(),
+            Some(StateError::InvalidMagic));
+        assert_eq!(StateReader::new(b"ERKI\xFF\x00\x00\x00\x00\x00").err(),
+            Some(StateError::UnsupportedVersion(0xFF)));
+
+        let mut state = StateWriter::new();
+        state.write_u32(0);
+        let mut state = state.finish();
+        state.pop();
+        assert_eq!(StateReader::new(&state).err(), Some(StateError::UnexpectedEnd));
+    }
+
+    #[test]
+    fn sized_bytes_must_match() {
+        let mut writer = StateWriter::new();
+        writer.write_sized_bytes(&[1, 2, 3]);
+        let state = writer.finish();
+        let mut reader = StateReader::new(&state).unwrap();
+        let mut bytes = [0; 4];
+        assert_eq!(reader.read_sized_bytes(&mut bytes), Err(StateError::LengthMismatch));
+    }
+}
@@ -0,0 +1,112 @@
+use std::fs::File;
+use std::convert::TryFrom;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/* 16 bit PCM stereo WAV file
+ * RIFF header, "fmt " chunk and a single "data" chunk.
+ * The chunk sizes are unknown until recording stops, they are patched in by finish()
+ */
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+const HEADER_SIZE: u32 = 44;
+
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    data_size: u32,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create(path: &Path, sample_rate: u32) -> io::Result<WavWriter<BufWriter<File>>> {
+        WavWriter::new(BufWriter::new(File::create(path)?), sample_rate)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut writer: W, sample_rate: u32) -> io::Result<WavWriter<W>> {
+        let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&1u16.to_le_bytes())?; //PCM
+        writer.write_all(&CHANNELS.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter {
+            writer,
+            data_size: 0,
+        })
+    }
+
+    /* Interleaved left and right samples in the range -1.0 to 1.0, as produced by the APU.
+     * The RIFF size fields are 32 bit, nothing is written once a file would pass 4 GiB
+     */
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        let data_size = u32::try_from(samples.len()).ok()
+            .and_then(|length| length.checked_mul((BITS_PER_SAMPLE / 8) as u32))
+            .and_then(|bytes| bytes.checked_add(self.data_size))
+            .filter(|size| size.checked_add(HEADER_SIZE - 8).is_some())
+            .ok_or_else(|| io::Error::other("WAV file size limit of 4 GiB reached"))?;
+
+        for sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.writer.write_all(&value.to_le_bytes())?;
+        }
+        self.data_size = data_size;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(HEADER_SIZE as u64 - 4))?;
+        self.writer.write_all(&self.data_size.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn writes_header_and_samples() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+        wav.write_samples(&[0.0, 1.0, -1.0, 2.0]).unwrap();
+        let data = wav.finish().unwrap().into_inner();
+
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(&data[4..8], &44u32.to_le_bytes());
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(&data[22..24], &2u16.to_le_bytes());
+        assert_eq!(&data[24..28], &44100u32.to_le_bytes());
+        assert_eq!(&data[28..32], &(44100u32 * 4).to_le_bytes());
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(&data[40..44], &8u32.to_le_bytes());
+        assert_eq!(&data[44..], &[0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80, 0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn stops_at_riff_size_limit() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+        wav.data_size = u32::MAX - 36 - 4;
+        assert!(wav.write_samples(&[0.0, 0.0]).is_ok());
+        assert!(wav.write_samples(&[0.0]).is_err());
+        assert_eq!(wav.data_size, u32::MAX - 36);
+        assert_eq!(wav.writer.get_ref().len(), 44 + 4);
+    }
+}